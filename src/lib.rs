@@ -3,23 +3,41 @@
 /// Usage:
 /// let expression = "2 + 3 * 4";
 /// let f = evaluate_expression(expression).unwrap();
-use regex::{CaptureMatches, Captures, Regex};
-use std::collections::VecDeque;
+/// The expression can also be parsed into an expression tree (Expr) to be inspected, transformed and evaluated later:
+/// let tree = parse(expression).unwrap();
+/// let f = tree.eval().unwrap();
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::sync::Arc;
 
 /// Enum Token represents different types of tokens in the RPN expression:
 /// Number represents a number value, which is stored as a floating-point number (f64).
-/// Operator represents an operator (e.g., +, -, *, /) and stores the operator as a string.
-/// Function represents a mathematical function (e.g., sin, cos, tan) and stores the function name as a string.
-/// LeftParen and RightParen represent parentheses, which are used to group expressions.
+/// Operator represents a binary operator (e.g., +, -, *, /, &, xor) and stores the operator as a string.
+/// UnaryOperator represents a prefix operator (-, +, ~, not) and stores the operator as a string.
+/// PostfixOperator represents an operator written after its operand (! for the factorial, ° for an angle in degrees) and stores the operator as a string.
+/// Function represents a call to a mathematical function (e.g., sin, cos, tan) and stores the function name as a string and its number of arguments, known once the call is closed in to_rpn.
+/// Constant represents a named constant (PI, E or a constant registered on an Evaluator) and stores the constant name with its value.
+/// Variable represents any other name, whose value is provided by a Context when the expression is evaluated.
+/// LeftParen and RightParen represent parentheses, which are used to group expressions and arguments.
+/// Comma separates the arguments of a function.
+/// Question and Colon separate the condition and the branches of a conditional (c ? a : b). In to_rpn and in the RPN expression, a Colon (with the span of its `?`) stands for the whole conditional.
 #[derive(Debug, Clone)]
 enum Token {
     Number(f64),
     Operator(String),
-    Function(String),
+    UnaryOperator(String),
+    PostfixOperator(String),
+    Function(String, usize),
+    Constant(String, f64),
+    Variable(String),
     LeftParen,
     RightParen,
+    Comma,
+    Question,
+    Colon,
 }
 
 /// Implementing Display trait for Token enum. useful for debug
@@ -27,257 +45,1894 @@ impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Token::Number(n) => write!(f, "{}", n),
-            Token::Operator(op) => write!(f, "{}", op),
-            Token::Function(func) => write!(f, "{}", func),
+            Token::Operator(op) | Token::UnaryOperator(op) | Token::PostfixOperator(op) => {
+                write!(f, "{}", op)
+            }
+            Token::Function(func, _) => write!(f, "{}", func),
+            Token::Constant(name, _) => write!(f, "{}", name),
+            Token::Variable(name) => write!(f, "{}", name),
             Token::LeftParen => write!(f, "("),
             Token::RightParen => write!(f, ")"),
+            Token::Question => write!(f, "?"),
+            Token::Colon => write!(f, ":"),
+            Token::Comma => write!(f, ","),
         }
     }
 }
 
 impl Token {
+    /// Returns true if the token can be the last token of an operand: a number, constant, variable, `)` or postfix operator
+    fn ends_operand(&self) -> bool {
+        matches!(
+            self,
+            Token::Number(_)
+                | Token::Constant(..)
+                | Token::Variable(_)
+                | Token::RightParen
+                | Token::PostfixOperator(_)
+        )
+    }
+
     ///returns an integer that represents how strongly an operator or function binds to its operands. Operators have higher precedence than functions and multiplication/division have higher precedence than addition/subtraction
+    /// The bitwise operators bind less than the arithmetic ones: shifts, then &, xor and |. Comparisons bind less than all of them.
+    /// The logical operators bind the least: not, then and, then or (the lowest).
+    /// The prefix operators -, + and ~ bind tighter than * and / but less than ^, so -2^2 is -(2^2).
     fn precedence(&self) -> i32 {
         match self {
             Token::Operator(op) => match op.as_str() {
-                "+" | "-" => 1,
-                "*" | "/" => 2,
-                "^" => 3,
+                "or" => 1,
+                "and" => 2,
+                "<" | "<=" | ">" | ">=" | "==" | "!=" => 4,
+                "|" => 5,
+                "xor" => 6,
+                "&" => 7,
+                "<<" | ">>" => 8,
+                "+" | "-" => 9,
+                "*" | "/" | "%" | "//" => 10,
+                "^" => 12,
                 _ => 0,
             },
-            Token::Function(_) => 4,
+            Token::UnaryOperator(op) => match op.as_str() {
+                "not" => 3,
+                _ => 11,
+            },
+            Token::Function(..) => 13,
             _ => 0,
         }
     }
 
-    fn to_string(&self) -> String {
+    /// Returns true if the operator groups from the right: 2^3^2 is 2^(3^2)
+    fn is_right_associative(&self) -> bool {
+        matches!(self, Token::Operator(op) if op == "^")
+    }
+}
+
+/// Span is the position of a token, an expression or an error in the original expression,
+/// as a range of byte offsets: start is inclusive and end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// Returns the smallest span that covers both spans
+    pub fn merge(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// Enum BtMathError represents the different reasons an expression can fail to be parsed, compiled or evaluated:
+/// UnexpectedCharacter is a character that is not part of any valid token.
+/// UnknownFunction is a function name that is not defined.
+/// UnknownSymbol is a variable (or constant) name without a value.
+/// UnknownOperator is an operator symbol that is not supported.
+/// UnbalancedParens is a parenthesis without its matching pair: a `(` that is never closed or a `)` that closes nothing.
+/// MissingOperand is an operator or function without enough values to apply to.
+/// MissingOperator is a value that is not combined with the rest of the expression (e.g., two numbers in a row).
+/// EmptyExpression is an expression that does not produce any value.
+/// ArityMismatch is a function called with the wrong number of arguments.
+/// DomainError is an operator or a function applied to a value it is not defined for (e.g., 1 % 0, sqrt(-1) or ln(0)), with the offending value.
+/// InvalidNumber is a hexadecimal (0x), binary (0b) or octal (0o) literal with no digits, an invalid digit or a value above 2^53 (the largest integer an f64 holds exactly).
+/// BuiltinConstant is a constant registered with the name of a built-in constant (PI, E) while overriding them is not allowed.
+/// InvalidName is a function or a constant registered with a name that could never be used in an expression: not an identifier, a keyword (and, or, not, xor, if, inf, infinity, nan) or the name of a constant.
+/// Every error carries the Span of the part of the expression that caused it. BuiltinConstant and InvalidName are not about an expression: their Span covers the registered name itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BtMathError {
+    UnexpectedCharacter {
+        character: char,
+        span: Span,
+    },
+    UnknownFunction {
+        name: String,
+        span: Span,
+    },
+    UnknownSymbol {
+        name: String,
+        span: Span,
+    },
+    UnknownOperator {
+        symbol: String,
+        span: Span,
+    },
+    UnbalancedParens {
+        paren: char,
+        span: Span,
+    },
+    MissingOperand {
+        span: Span,
+    },
+    MissingOperator {
+        span: Span,
+    },
+    EmptyExpression {
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    DomainError {
+        operation: String,
+        value: f64,
+        span: Span,
+    },
+    InvalidNumber {
+        literal: String,
+        span: Span,
+    },
+    BuiltinConstant {
+        name: String,
+        span: Span,
+    },
+    InvalidName {
+        name: String,
+        span: Span,
+    },
+}
+
+impl BtMathError {
+    /// Returns the position in the original expression of the part that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            BtMathError::UnexpectedCharacter { span, .. }
+            | BtMathError::UnknownFunction { span, .. }
+            | BtMathError::UnknownSymbol { span, .. }
+            | BtMathError::UnknownOperator { span, .. }
+            | BtMathError::UnbalancedParens { span, .. }
+            | BtMathError::MissingOperand { span }
+            | BtMathError::MissingOperator { span }
+            | BtMathError::EmptyExpression { span }
+            | BtMathError::ArityMismatch { span, .. }
+            | BtMathError::DomainError { span, .. }
+            | BtMathError::InvalidNumber { span, .. }
+            | BtMathError::BuiltinConstant { span, .. }
+            | BtMathError::InvalidName { span, .. } => *span,
+        }
+    }
+}
+
+/// Implementing Display trait for BtMathError so the error can be shown to a user
+impl fmt::Display for BtMathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BtMathError::UnexpectedCharacter { character, .. } => {
+                write!(f, "unexpected character `{}`", character)?
+            }
+            BtMathError::UnknownFunction { name, .. } => write!(f, "unknown function `{}`", name)?,
+            BtMathError::UnknownSymbol { name, .. } => write!(f, "unknown variable `{}`", name)?,
+            BtMathError::UnknownOperator { symbol, .. } => {
+                write!(f, "unknown operator `{}`", symbol)?
+            }
+            BtMathError::UnbalancedParens { paren: '(', .. } => write!(f, "unclosed `(`")?,
+            BtMathError::UnbalancedParens { paren, .. } => write!(f, "unmatched `{}`", paren)?,
+            BtMathError::MissingOperand { .. } => write!(f, "missing operand")?,
+            BtMathError::MissingOperator { .. } => write!(f, "missing operator between values")?,
+            BtMathError::EmptyExpression { .. } => return write!(f, "empty expression"),
+            BtMathError::ArityMismatch {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "function `{}` expects {} argument(s), found {}",
+                name, expected, found
+            )?,
+            BtMathError::DomainError {
+                operation, value, ..
+            } => write!(f, "`{}` is not defined for {}", operation, value)?,
+            BtMathError::InvalidNumber { literal, .. } => {
+                write!(f, "invalid number `{}`", literal)?
+            }
+            BtMathError::BuiltinConstant { name, .. } => {
+                return write!(f, "`{}` is a built-in constant", name);
+            }
+            BtMathError::InvalidName { name, .. } => {
+                return write!(f, "`{}` cannot be used as a name", name);
+            }
+        }
+        write!(f, " at position {}", self.span().start)
+    }
+}
+
+impl std::error::Error for BtMathError {}
+
+/// Expr is the parsed form of an expression (an expression tree) returned by `parse`.
+/// Every node has a kind and the Span of the original expression it was parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+/// Enum ExprKind represents the different kinds of nodes of an expression tree:
+/// Number is a numeric literal.
+/// Constant is a named constant (PI, E or a constant registered on an Evaluator) stored by its upper case name, with the value it had when the expression was parsed.
+/// Variable is a name whose value is looked up in a Context when the expression is evaluated.
+/// Unary applies a unary operator to a single operand.
+/// Binary applies a binary operator to a left and a right operand.
+/// Function is a call to a mathematical function (e.g., sin, cos) stored by its lower case name with its arguments.
+/// Conditional is `if(condition, then, otherwise)` or `condition ? then : otherwise`: only the branch chosen by the condition (true if not 0) is evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(f64),
+    Constant {
+        name: String,
+        value: f64,
+    },
+    Variable(String),
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Conditional {
+        condition: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+}
+
+/// Unary operators that can appear in an expression tree.
+/// Neg and Plus are the signs written before an operand (-x, +x).
+/// BitNot is the bitwise complement of an integer.
+/// Factorial is the postfix operator `!`, extended to non-integers with the gamma function (x! = gamma(x + 1)).
+/// Not is the logical negation: 1 if the operand is 0, else 0.
+/// Degree is the postfix `°` of an angle written in degrees (45°). apply converts it to radians; an Evaluator converts it to the unit of its AngleMode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+    BitNot,
+    Factorial,
+    Not,
+    Degree,
+}
+
+/// Binary operators that can appear in an expression tree.
+/// Rem is the remainder of the truncated division: it has the sign of the dividend (-7 % 3 = -1), like in Rust and C.
+/// FloorDiv is the division rounded down to an integer (-7 // 2 = -4).
+/// BitAnd, BitOr, BitXor, Shl and Shr are bitwise operators and are only defined for integers between -2^53 and 2^53.
+/// Less, LessEqual, Greater, GreaterEqual, Equal and NotEqual are comparisons: their result is 1 when true and 0 when false.
+/// And and Or are logical operators: any value other than 0 is true and their result is 1 or 0. Their right operand is only evaluated when the left one does not decide the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    FloorDiv,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+impl UnaryOp {
+    /// Returns the operator written as `symbol` or None if it is not a unary operator
+    fn from_symbol(symbol: &str) -> Option<UnaryOp> {
+        match symbol {
+            "-" => Some(UnaryOp::Neg),
+            "+" => Some(UnaryOp::Plus),
+            "~" => Some(UnaryOp::BitNot),
+            "not" => Some(UnaryOp::Not),
+            _ => None,
+        }
+    }
+
+    /// Returns the operator written as `symbol` after its operand or None if it is not a postfix operator
+    fn from_postfix_symbol(symbol: &str) -> Option<UnaryOp> {
+        match symbol {
+            "!" => Some(UnaryOp::Factorial),
+            "°" => Some(UnaryOp::Degree),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the operator is written after its operand
+    pub fn is_postfix(&self) -> bool {
+        matches!(self, UnaryOp::Factorial | UnaryOp::Degree)
+    }
+
+    /// Returns the symbol used to write the operator
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Plus => "+",
+            UnaryOp::BitNot => "~",
+            UnaryOp::Factorial => "!",
+            UnaryOp::Not => "not",
+            UnaryOp::Degree => "°",
+        }
+    }
+
+    /// Applies the operator to its operand
+    /// Fails with the operand if the operator is not defined for it (e.g., ~ on a non-integral value or ! on a negative integer).
+    pub fn apply(&self, operand: f64) -> Result<f64, f64> {
         match self {
-            Token::Number(num) => num.to_string(),
-            Token::Operator(op) => op.clone(),
-            Token::Function(func) => func.clone(),
-            Token::LeftParen => String::from("("),
-            Token::RightParen => String::from(")"),
+            UnaryOp::Neg => Ok(-operand),
+            UnaryOp::Plus => Ok(operand),
+            UnaryOp::BitNot => Ok(!to_integer(operand)? as f64),
+            UnaryOp::Factorial if operand < 0.0 && operand.fract() == 0.0 => Err(operand),
+            UnaryOp::Factorial => Ok(gamma(operand + 1.0)),
+            UnaryOp::Not => Ok(boolean(operand == 0.0)),
+            UnaryOp::Degree => Ok(operand.to_radians()),
+        }
+    }
+}
+
+impl BinaryOp {
+    /// Returns the operator written as `symbol` or None if it is not a binary operator
+    fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        match symbol {
+            "+" => Some(BinaryOp::Add),
+            "-" => Some(BinaryOp::Sub),
+            "*" => Some(BinaryOp::Mul),
+            "/" => Some(BinaryOp::Div),
+            "%" => Some(BinaryOp::Rem),
+            "//" => Some(BinaryOp::FloorDiv),
+            "^" => Some(BinaryOp::Pow),
+            "&" => Some(BinaryOp::BitAnd),
+            "|" => Some(BinaryOp::BitOr),
+            "xor" => Some(BinaryOp::BitXor),
+            "<<" => Some(BinaryOp::Shl),
+            ">>" => Some(BinaryOp::Shr),
+            "<" => Some(BinaryOp::Less),
+            "<=" => Some(BinaryOp::LessEqual),
+            ">" => Some(BinaryOp::Greater),
+            ">=" => Some(BinaryOp::GreaterEqual),
+            "==" => Some(BinaryOp::Equal),
+            "!=" => Some(BinaryOp::NotEqual),
+            "and" => Some(BinaryOp::And),
+            "or" => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+
+    /// Returns the symbol used to write the operator
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::FloorDiv => "//",
+            BinaryOp::Pow => "^",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "xor",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+        }
+    }
+
+    /// Applies the operator to its left and right operands
+    /// Fails with the operand the operator is not defined for: a zero divisor of % or //, a non-integral operand of a bitwise operator or one above 2^53 in magnitude, a shift amount outside 0..64,
+    /// or the left operand of a bitwise operator whose result would be above 2^53 in magnitude (1 << 63).
+    pub fn apply(&self, a: f64, b: f64) -> Result<f64, f64> {
+        match self {
+            BinaryOp::Add => Ok(a + b),
+            BinaryOp::Sub => Ok(a - b),
+            BinaryOp::Mul => Ok(a * b),
+            BinaryOp::Div => Ok(a / b),
+            BinaryOp::Rem if b == 0.0 => Err(b),
+            BinaryOp::Rem => Ok(a % b),
+            BinaryOp::FloorDiv if b == 0.0 => Err(b),
+            BinaryOp::FloorDiv => Ok((a / b).floor()),
+            BinaryOp::Pow => Ok(a.powf(b)),
+            BinaryOp::BitAnd => Ok((to_integer(a)? & to_integer(b)?) as f64),
+            BinaryOp::BitOr => from_integer((to_integer(a)? | to_integer(b)?) as i128, a),
+            BinaryOp::BitXor => from_integer((to_integer(a)? ^ to_integer(b)?) as i128, a),
+            BinaryOp::Shl => from_integer((to_integer(a)? as i128) << shift_amount(b)?, a),
+            BinaryOp::Shr => Ok((to_integer(a)? >> shift_amount(b)?) as f64),
+            BinaryOp::Less => Ok(boolean(a < b)),
+            BinaryOp::LessEqual => Ok(boolean(a <= b)),
+            BinaryOp::Greater => Ok(boolean(a > b)),
+            BinaryOp::GreaterEqual => Ok(boolean(a >= b)),
+            BinaryOp::Equal => Ok(boolean(a == b)),
+            BinaryOp::NotEqual => Ok(boolean(a != b)),
+            BinaryOp::And => Ok(boolean(a != 0.0 && b != 0.0)),
+            BinaryOp::Or => Ok(boolean(a != 0.0 || b != 0.0)),
+        }
+    }
+
+    /// Returns the result of the operator when the left operand alone decides it (0 and x, 1 or x), or None if the right operand is needed
+    pub fn short_circuit(&self, a: f64) -> Option<f64> {
+        match self {
+            BinaryOp::And if a == 0.0 => Some(0.0),
+            BinaryOp::Or if a != 0.0 => Some(1.0),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the number that represents a boolean: 1 for true and 0 for false
+fn boolean(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Returns the value as an integer, or fails with the value if it is not integral or if its magnitude is above 2^53:
+/// such a value is already rounded and its low bits are lost
+fn to_integer(value: f64) -> Result<i64, f64> {
+    if value.fract() == 0.0 && value.abs() <= MAX_EXACT_INTEGER as f64 {
+        Ok(value as i64)
+    } else {
+        Err(value)
+    }
+}
+
+/// Returns the result of a bitwise operator as a number, or fails with its left operand if the result is above 2^53 in magnitude
+/// (e.g., 1 << 60): the result could not be used as an operand of another bitwise operator
+fn from_integer(result: i128, operand: f64) -> Result<f64, f64> {
+    if result.unsigned_abs() <= MAX_EXACT_INTEGER as u128 {
+        Ok(result as f64)
+    } else {
+        Err(operand)
+    }
+}
+
+/// Returns the number of bits to shift by, or fails with the value if it is not an integer in 0..64
+fn shift_amount(value: f64) -> Result<u32, f64> {
+    match to_integer(value) {
+        Ok(bits) if (0..64).contains(&bits) => Ok(bits as u32),
+        _ => Err(value),
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+
+    /// Evaluate the expression tree and returns the result as a Float
+    /// Fails if the expression uses any variable. Use eval_with to provide their values.
+    /// Only the built-in functions are available; use Evaluator::eval for an evaluator's own functions.
+    pub fn eval(&self) -> Result<f64, BtMathError> {
+        self.eval_with(&Context::new())
+    }
+
+    /// Evaluate the expression tree taking the values of its variables from `ctx` and returns the result as a Float
+    pub fn eval_with(&self, ctx: &Context) -> Result<f64, BtMathError> {
+        Evaluator::new().eval(self, ctx)
+    }
+}
+
+/// Implementing Display trait for Expr. Sub-expressions are fully parenthesized so the output can be parsed back.
+/// Infinity and NaN are written 1e309 and (0 / 0), which are read back as the same values whether special floats are enabled or not.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            // inf and nan are only read as numbers with special floats enabled: write them as expressions that always give them
+            ExprKind::Number(n) if n.is_nan() => write!(f, "(0 / 0)"),
+            ExprKind::Number(n) if *n == f64::INFINITY => write!(f, "1e309"),
+            ExprKind::Number(n) if *n == f64::NEG_INFINITY => write!(f, "(-1e309)"),
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::Constant { name, .. } => write!(f, "{}", name),
+            ExprKind::Variable(name) => write!(f, "{}", name),
+            // ° is only read right after a number: 45°
+            ExprKind::Unary {
+                op: UnaryOp::Degree,
+                operand,
+            } => write!(f, "{}{}", operand, UnaryOp::Degree.symbol()),
+            ExprKind::Unary { op, operand } if op.is_postfix() => {
+                write!(f, "({}){}", operand, op.symbol())
+            }
+            ExprKind::Unary { op, operand } => write!(f, "{}({})", op.symbol(), operand),
+            ExprKind::Binary { op, lhs, rhs } => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+            ExprKind::Function { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "if({}, {}, {})", condition, then, otherwise),
         }
     }
 }
 
 /// Public function that evaluate a mathematical expression with a combination of basic arithmetic operations and mathematical functions
-/// It strips spaces, tokenizes the input string, converts it to RPN, and then evaluates the RPN expression.
+/// It parses the input string into an expression tree and then evaluates the tree.
 /// Returns the results as a Float
-pub fn evaluate_expression(expression: &str) -> Result<f64, String> {
-    let expression = replace_key_words(&expression.replace(" ", "")); // Remove spaces
-    let tokens = tokenize(&expression)?;
-    let rpn = to_rpn(&tokens)?;
-    evaluate_rpn(&rpn)
-}
-
-///This function will match specific keywords and replace them accordingly
-/// Case 1: match the expressions in the format pow(#1,#2) and replace them with just #1^#2
-fn replace_key_words(input: &str) -> String {
-    //match the expressions in the format pow(#1,#2) and replace them with just #1^#2
-    let regex = Regex::new(r"(?i)pow\s*\(\s*(-?\d+(\.\d*)?)\s*,\s*(-?\d+(\.\d*)?)\s*\)").unwrap();
-
-    // Replace all matches with the desired format
-    regex
-        .replace_all(&input, |caps: &Captures| {
-            let base = &caps[1];
-            let exponent = &caps[3];
-            format!("{}^{}", base, exponent)
+pub fn evaluate_expression(expression: &str) -> Result<f64, BtMathError> {
+    Evaluator::new().evaluate(expression)
+}
+
+/// Public function that evaluate a mathematical expression that uses variables (e.g., "x * 2").
+/// The value of every variable is taken from `ctx`; an unbound variable is an error.
+/// Usage:
+/// let mut ctx = Context::new();
+/// ctx.set("x", 3.0);
+/// let f = evaluate_with("x * 2", &ctx).unwrap();
+pub fn evaluate_with(expression: &str, ctx: &Context) -> Result<f64, BtMathError> {
+    Evaluator::new().evaluate_with(expression, ctx)
+}
+
+/// Public function that parses a mathematical expression into an expression tree (Expr) without evaluating it.
+/// See Evaluator::parse.
+pub fn parse(expression: &str) -> Result<Expr, BtMathError> {
+    Evaluator::new().parse(expression)
+}
+
+/// Public function that parses and compiles a mathematical expression once so it can be evaluated many times.
+/// See CompiledExpression.
+pub fn compile(expression: &str) -> Result<CompiledExpression, BtMathError> {
+    Evaluator::new().compile(expression)
+}
+
+/// An Evaluator parses, evaluates and compiles expressions using the built-in functions and constants plus the ones registered on it.
+/// The public functions evaluate_expression, evaluate_with, parse and compile use an Evaluator without registered functions or constants.
+/// Usage:
+/// let mut evaluator = Evaluator::new();
+/// evaluator.register_function("lerp", 3, |a| a[0] + (a[1] - a[0]) * a[2]).unwrap();
+/// let f = evaluator.evaluate("lerp(10, 20, 0.25)").unwrap();
+#[derive(Debug, Clone, Default)]
+pub struct Evaluator {
+    functions: HashMap<String, Function>,
+    constants: HashMap<String, f64>,
+    allow_constant_override: bool,
+    implicit_multiplication: bool,
+    special_floats: bool,
+    angle_mode: AngleMode,
+    rounding_mode: RoundingMode,
+}
+
+/// Enum AngleMode is the unit of the angles taken by the trigonometric functions (sin, cos, tan, sec, csc, cot) and returned by their inverses (asin, acos, atan, atan2, asec, acsc, acot):
+/// Radians (the default), Degrees (a full turn is 360) or Gradians (a full turn is 400).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+    Gradians,
+}
+
+impl AngleMode {
+    /// Returns an angle given in this unit in radians
+    fn to_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_radians(),
+            AngleMode::Gradians => angle * std::f64::consts::PI / 200.0,
+        }
+    }
+
+    /// Returns an angle given in radians in this unit
+    fn radians_to_unit(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_degrees(),
+            AngleMode::Gradians => angle * 200.0 / std::f64::consts::PI,
+        }
+    }
+}
+
+/// Enum RoundingMode is how the round function rounds a value halfway between two integers:
+/// HalfAwayFromZero (the default) rounds it away from zero: 2.5 to 3 and -2.5 to -3.
+/// HalfEven rounds it to the even integer (banker's rounding): 2.5 to 2, 3.5 to 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    #[default]
+    HalfAwayFromZero,
+    HalfEven,
+}
+
+impl RoundingMode {
+    /// Returns the value rounded to an integer
+    fn round(self, value: f64) -> f64 {
+        match self {
+            RoundingMode::HalfAwayFromZero => value.round(),
+            RoundingMode::HalfEven => value.round_ties_even(),
+        }
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Evaluator {
+        Evaluator::default()
+    }
+
+    /// Register a function that takes `arity` arguments, so it can be called from expressions like a built-in function.
+    /// The function receives the evaluated arguments in order. Names are case insensitive and a registered function replaces a built-in function with the same name.
+    /// Fails with BtMathError::InvalidName if the name is not an identifier, is a keyword (e.g., if, not) or is the name of a constant.
+    pub fn register_function<F>(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: F,
+    ) -> Result<(), BtMathError>
+    where
+        F: Fn(&[f64]) -> f64 + Send + Sync + 'static,
+    {
+        if !is_identifier(name) || is_keyword(name) || self.constant(name).is_some() {
+            return Err(invalid_name(name));
+        }
+        self.functions.insert(
+            name.to_lowercase(),
+            Function {
+                arity: arity..=arity,
+                implementation: Implementation::Custom(Arc::new(function)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Register a named constant (e.g., TAX_RATE, G) that can be used in expressions like PI and E.
+    /// Names are case insensitive. Constants are resolved when an expression is parsed, so changing a constant does not change expressions already parsed or compiled.
+    /// Fails with BtMathError::BuiltinConstant if the name is a built-in constant, unless allow_constant_override was enabled.
+    /// Fails with BtMathError::InvalidName if the name is not an identifier or is a keyword (e.g., and, not), or if it is the name of a function
+    /// (built-in or registered) unless allow_constant_override was enabled: the constant would then hide the function.
+    pub fn register_constant(&mut self, name: &str, value: f64) -> Result<(), BtMathError> {
+        if !is_identifier(name)
+            || is_keyword(name)
+            || (!self.allow_constant_override && self.function(name).is_some())
+        {
+            return Err(invalid_name(name));
+        }
+        if !self.allow_constant_override && evaluate_const(name).is_ok() {
+            return Err(BtMathError::BuiltinConstant {
+                name: name.to_owned(),
+                span: Span::new(0, name.len()),
+            });
+        }
+        self.constants.insert(name.to_uppercase(), value);
+        Ok(())
+    }
+
+    /// Allow (or forbid again) register_constant to replace the built-in constants PI and E, or to hide a function with a constant of the same name
+    pub fn allow_constant_override(&mut self, allow: bool) {
+        self.allow_constant_override = allow;
+    }
+
+    /// Enable (or disable) implicit multiplication: two operands written next to each other are multiplied, e.g., 2PI, 3(4+5), (1+2)(3+4) or 2sin(x).
+    /// An implicit multiplication has the same precedence as `*` and is applied from left to right like it, so 1/2PI is (1/2)*PI.
+    /// A name followed by `(` is still a function call, so x(2) is not x*2.
+    /// It is disabled by default: operands next to each other are then a MissingOperator error.
+    pub fn set_implicit_multiplication(&mut self, enabled: bool) {
+        self.implicit_multiplication = enabled;
+    }
+
+    /// Enable (or disable) the keywords inf, infinity and nan (in any case) as number literals.
+    /// It is disabled by default so these names can be used as variables.
+    pub fn set_special_floats(&mut self, enabled: bool) {
+        self.special_floats = enabled;
+    }
+
+    /// Set the unit of the angles of the trigonometric functions. See AngleMode.
+    /// A number followed by ° (e.g., 45°) is an angle in degrees whatever the mode: it is converted to the unit of the mode of the evaluator that evaluates or compiles the expression.
+    pub fn set_angle_mode(&mut self, mode: AngleMode) {
+        self.angle_mode = mode;
+    }
+
+    /// Set how round rounds values halfway between two integers. See RoundingMode.
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.rounding_mode = mode;
+    }
+
+    /// Returns all the constants currently defined, built-in and registered, by their upper case name and sorted by name.
+    pub fn constants(&self) -> Vec<(String, f64)> {
+        let mut names: Vec<String> = ["PI", "E"]
+            .into_iter()
+            .map(str::to_owned)
+            .chain(self.constants.keys().cloned())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let value = self.constant(&name)?;
+                Some((name, value))
+            })
+            .collect()
+    }
+
+    /// Parses a mathematical expression into an expression tree (Expr) without evaluating it.
+    /// It tokenizes the input string, converts it to RPN, and then builds the tree from the RPN expression.
+    /// The expression can span several lines: any whitespace separates tokens.
+    /// Spans in the tree (and in errors) refer to the original expression.
+    pub fn parse(&self, expression: &str) -> Result<Expr, BtMathError> {
+        let mut tokens = tokenize(self, expression)?;
+        if self.implicit_multiplication {
+            tokens = insert_implicit_multiplication(tokens);
+        }
+        let rpn = to_rpn(&tokens)?;
+        build_ast(self, &rpn, Span::new(0, expression.len()))
+    }
+
+    /// Evaluate a mathematical expression and returns the result as a Float
+    pub fn evaluate(&self, expression: &str) -> Result<f64, BtMathError> {
+        self.evaluate_with(expression, &Context::new())
+    }
+
+    /// Evaluate a mathematical expression taking the values of its variables from `ctx` and returns the result as a Float
+    pub fn evaluate_with(&self, expression: &str, ctx: &Context) -> Result<f64, BtMathError> {
+        self.eval(&self.parse(expression)?, ctx)
+    }
+
+    /// Parses and compiles a mathematical expression once so it can be evaluated many times.
+    pub fn compile(&self, expression: &str) -> Result<CompiledExpression, BtMathError> {
+        self.compile_expr(&self.parse(expression)?)
+    }
+
+    /// Evaluate an expression tree taking the values of its variables from `ctx` and returns the result as a Float
+    pub fn eval(&self, expr: &Expr, ctx: &Context) -> Result<f64, BtMathError> {
+        match &expr.kind {
+            ExprKind::Number(value) => Ok(*value),
+            ExprKind::Constant { value, .. } => Ok(*value),
+            ExprKind::Variable(name) => lookup_variable(&ctx.variables, name, expr.span),
+            // An angle in degrees is converted to the unit of the angle mode of this evaluator
+            ExprKind::Unary {
+                op: UnaryOp::Degree,
+                operand,
+            } => Ok(self
+                .angle_mode
+                .radians_to_unit(self.eval(operand, ctx)?.to_radians())),
+            ExprKind::Unary { op, operand } => op
+                .apply(self.eval(operand, ctx)?)
+                .map_err(|value| domain_error(op.symbol(), value, expr.span)),
+            ExprKind::Binary { op, lhs, rhs } => {
+                let a = self.eval(lhs, ctx)?;
+                if let Some(result) = op.short_circuit(a) {
+                    return Ok(result);
+                }
+                op.apply(a, self.eval(rhs, ctx)?)
+                    .map_err(|value| domain_error(op.symbol(), value, expr.span))
+            }
+            ExprKind::Function { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, ctx))
+                    .collect::<Result<Vec<f64>, BtMathError>>()?;
+                let function = self.function_for_call(name, args.len(), expr.span)?;
+                function
+                    .call(&args)
+                    .map_err(|value| domain_error(name, value, expr.span))
+            }
+            ExprKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                if self.eval(condition, ctx)? != 0.0 {
+                    self.eval(then, ctx)
+                } else {
+                    self.eval(otherwise, ctx)
+                }
+            }
+        }
+    }
+
+    /// Compile an expression tree into a CompiledExpression
+    pub fn compile_expr(&self, expr: &Expr) -> Result<CompiledExpression, BtMathError> {
+        let mut program = Vec::new();
+        emit_instructions(self, expr, &mut program)?;
+
+        // Compute the stack size the program needs. A tree always leaves a single value on the stack.
+        // The branches of a conditional are counted one after the other: Jump removes the value of the first branch, as the second one pushes it instead
+        let mut depth: usize = 0;
+        let mut max_depth: usize = 0;
+        for instruction in &program {
+            match instruction {
+                Instruction::Push(_) | Instruction::Load(..) => depth += 1,
+                Instruction::Unary(..) | Instruction::ShortCircuit(..) => {}
+                Instruction::Binary(..) | Instruction::JumpIfZero(_) | Instruction::Jump(_) => {
+                    depth -= 1
+                }
+                Instruction::Call(_, args, ..) => depth = depth + 1 - args,
+            };
+            max_depth = max_depth.max(depth);
+        }
+
+        Ok(CompiledExpression {
+            program,
+            max_depth,
+            span: expr.span,
+        })
+    }
+
+    /// Returns the value of a number literal, or None if the token is not a number
+    fn number(&self, token: &str) -> Option<f64> {
+        if let Some(radix) = radix_prefix(token) {
+            return u64::from_str_radix(&token[2..], radix)
+                .ok()
+                .map(|n| n as f64);
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            return f64::from_str(token).ok();
+        }
+        match token.to_lowercase().as_str() {
+            "inf" | "infinity" if self.special_floats => Some(f64::INFINITY),
+            "nan" if self.special_floats => Some(f64::NAN),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a constant by its name: a registered constant or else a built-in constant
+    fn constant(&self, name: &str) -> Option<f64> {
+        match self.constants.get(&name.to_uppercase()) {
+            Some(value) => Some(*value),
+            None => evaluate_const(name).ok(),
+        }
+    }
+
+    /// Returns a function by its name: a registered function or else a built-in function
+    fn function(&self, name: &str) -> Option<Function> {
+        match self.functions.get(&name.to_lowercase()) {
+            Some(function) => Some(function.clone()),
+            None => builtin_function(name, self.angle_mode, self.rounding_mode),
+        }
+    }
+
+    /// Returns the implementation of a function called with `args` arguments, or an error if the function does not exist or takes a different number of arguments.
+    /// `span` is the span of the function call, used to report errors.
+    fn function_for_call(
+        &self,
+        func: &str,
+        args: usize,
+        span: Span,
+    ) -> Result<Implementation, BtMathError> {
+        let function = self
+            .function(func)
+            .ok_or_else(|| BtMathError::UnknownFunction {
+                name: func.to_owned(),
+                span,
+            })?;
+        if !function.arity.contains(&args) {
+            // The closest number of arguments the function accepts
+            let expected = if args < *function.arity.start() {
+                *function.arity.start()
+            } else {
+                *function.arity.end()
+            };
+            return Err(BtMathError::ArityMismatch {
+                name: func.to_owned(),
+                expected,
+                found: args,
+                span,
+            });
+        }
+        Ok(function.implementation)
+    }
+}
+
+/// Enum Instruction is a single step of a CompiledExpression program, executed on a stack of values:
+/// Push pushes a number (constants are already resolved to their value).
+/// Load pushes the value of a variable taken from the Context. The span is used to report an unbound variable.
+/// Unary and Binary pop their operands and push the result of the operator. The span is used to report a value the operator is not defined for.
+/// Call pops the given number of arguments of a function and pushes its result. The function is resolved at compile time; its name and span are used to report an argument it is not defined for.
+/// ShortCircuit checks the left operand of a logical operator on top of the stack: if it decides the result, it is replaced by the result
+/// and the program continues at the given instruction (after the right operand and the operator), else the program continues with the right operand.
+/// JumpIfZero pops the condition of a conditional and continues at the given instruction (the second branch) if it is 0.
+/// Jump continues at the given instruction, skipping the second branch of a conditional after the first one.
+#[derive(Debug, Clone)]
+enum Instruction {
+    Push(f64),
+    Load(String, Span),
+    Unary(UnaryOp, Span),
+    Binary(BinaryOp, Span),
+    Call(Implementation, usize, String, Span),
+    ShortCircuit(BinaryOp, usize),
+    JumpIfZero(usize),
+    Jump(usize),
+}
+
+/// A CompiledExpression holds an expression already parsed, validated and translated into a flat list of instructions (RPN).
+/// Evaluating it does not tokenize or parse, only variables are looked up by name, and it does not allocate once the Context stack has grown to the required size.
+/// Usage:
+/// let compiled = compile("2 * PI * x").unwrap();
+/// let mut ctx = Context::new();
+/// ctx.set("x", 3.0);
+/// let f = compiled.eval(&mut ctx).unwrap();
+#[derive(Debug, Clone)]
+pub struct CompiledExpression {
+    program: Vec<Instruction>,
+    max_depth: usize,
+    span: Span,
+}
+
+/// Context holds the values of the variables used by an expression and the state used while evaluating a CompiledExpression.
+/// Variable names are case sensitive. Reuse the same Context between evaluations to reuse its stack.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    variables: HashMap<String, f64>,
+    stack: Vec<f64>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Bind `name` to `value`, replacing any previous value
+    pub fn set(&mut self, name: &str, value: f64) {
+        match self.variables.get_mut(name) {
+            Some(current) => *current = value,
+            None => {
+                self.variables.insert(name.to_owned(), value);
+            }
+        }
+    }
+
+    /// Returns the value bound to `name`, if any
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.variables.get(name).copied()
+    }
+}
+
+/// Returns the value bound to `name` or an unknown variable error
+fn lookup_variable(
+    variables: &HashMap<String, f64>,
+    name: &str,
+    span: Span,
+) -> Result<f64, BtMathError> {
+    variables
+        .get(name)
+        .copied()
+        .ok_or_else(|| BtMathError::UnknownSymbol {
+            name: name.to_owned(),
+            span,
         })
-        .to_string()
+}
+
+impl Expr {
+    /// Compile the expression tree into a CompiledExpression
+    pub fn compile(&self) -> Result<CompiledExpression, BtMathError> {
+        Evaluator::new().compile_expr(self)
+    }
+}
+
+/// Append the instructions that evaluate `expr` to `program` in Reverse Polish Notation (operands first)
+fn emit_instructions(
+    evaluator: &Evaluator,
+    expr: &Expr,
+    program: &mut Vec<Instruction>,
+) -> Result<(), BtMathError> {
+    match &expr.kind {
+        ExprKind::Number(value) => program.push(Instruction::Push(*value)),
+        ExprKind::Constant { value, .. } => program.push(Instruction::Push(*value)),
+        ExprKind::Variable(name) => program.push(Instruction::Load(name.clone(), expr.span)),
+        // An angle in degrees is converted to the unit of the angle mode of the evaluator that compiles it
+        ExprKind::Unary {
+            op: UnaryOp::Degree,
+            operand,
+        } => {
+            emit_instructions(evaluator, operand, program)?;
+            program.push(Instruction::Call(
+                Implementation::AngleOutput(|a| Ok(a[0].to_radians()), evaluator.angle_mode),
+                1,
+                UnaryOp::Degree.symbol().to_owned(),
+                expr.span,
+            ));
+        }
+        ExprKind::Unary { op, operand } => {
+            emit_instructions(evaluator, operand, program)?;
+            program.push(Instruction::Unary(*op, expr.span));
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            emit_instructions(evaluator, lhs, program)?;
+            let short_circuit = matches!(op, BinaryOp::And | BinaryOp::Or).then(|| {
+                program.push(Instruction::ShortCircuit(*op, 0));
+                program.len() - 1
+            });
+            emit_instructions(evaluator, rhs, program)?;
+            program.push(Instruction::Binary(*op, expr.span));
+            if let Some(index) = short_circuit {
+                program[index] = Instruction::ShortCircuit(*op, program.len());
+            }
+        }
+        ExprKind::Function { name, args } => {
+            let function = evaluator.function_for_call(name, args.len(), expr.span)?;
+            for arg in args {
+                emit_instructions(evaluator, arg, program)?;
+            }
+            program.push(Instruction::Call(
+                function,
+                args.len(),
+                name.clone(),
+                expr.span,
+            ));
+        }
+        ExprKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            emit_instructions(evaluator, condition, program)?;
+            let jump_if_zero = program.len();
+            program.push(Instruction::JumpIfZero(0));
+            emit_instructions(evaluator, then, program)?;
+            let jump = program.len();
+            program.push(Instruction::Jump(0));
+            program[jump_if_zero] = Instruction::JumpIfZero(program.len());
+            emit_instructions(evaluator, otherwise, program)?;
+            program[jump] = Instruction::Jump(program.len());
+        }
+    }
+    Ok(())
+}
+
+impl CompiledExpression {
+    /// Evaluate the compiled expression and returns the result as a Float
+    pub fn eval(&self, ctx: &mut Context) -> Result<f64, BtMathError> {
+        let Context { variables, stack } = ctx;
+        stack.clear();
+        stack.reserve(self.max_depth);
+
+        let mut next = 0;
+        while let Some(instruction) = self.program.get(next) {
+            next += 1;
+            match instruction {
+                Instruction::Push(value) => stack.push(*value),
+                Instruction::Load(name, span) => {
+                    stack.push(lookup_variable(variables, name, *span)?)
+                }
+                Instruction::Unary(op, span) => {
+                    let a = stack.pop().ok_or(self.missing_operand())?;
+                    let result = op
+                        .apply(a)
+                        .map_err(|value| domain_error(op.symbol(), value, *span))?;
+                    stack.push(result);
+                }
+                Instruction::Binary(op, span) => {
+                    let b = stack.pop().ok_or(self.missing_operand())?;
+                    let a = stack.pop().ok_or(self.missing_operand())?;
+                    let result = op
+                        .apply(a, b)
+                        .map_err(|value| domain_error(op.symbol(), value, *span))?;
+                    stack.push(result);
+                }
+                Instruction::Call(function, args, name, span) => {
+                    let first = stack
+                        .len()
+                        .checked_sub(*args)
+                        .ok_or(self.missing_operand())?;
+                    let result = function
+                        .call(&stack[first..])
+                        .map_err(|value| domain_error(name, value, *span))?;
+                    stack.truncate(first);
+                    stack.push(result);
+                }
+                Instruction::ShortCircuit(op, target) => {
+                    let a = stack.last_mut().ok_or(self.missing_operand())?;
+                    if let Some(result) = op.short_circuit(*a) {
+                        *a = result;
+                        next = *target;
+                    }
+                }
+                Instruction::JumpIfZero(target) => {
+                    if stack.pop().ok_or(self.missing_operand())? == 0.0 {
+                        next = *target;
+                    }
+                }
+                Instruction::Jump(target) => next = *target,
+            }
+        }
+
+        stack.pop().ok_or(self.missing_operand())
+    }
+
+    /// Error for a stack underflow. The program is validated when it is compiled so it is not expected to happen
+    fn missing_operand(&self) -> BtMathError {
+        BtMathError::MissingOperand { span: self.span }
+    }
+}
+
+/// Error for an operator or a function applied to a value it is not defined for
+fn domain_error(operation: &str, value: f64, span: Span) -> BtMathError {
+    BtMathError::DomainError {
+        operation: operation.to_owned(),
+        value,
+        span,
+    }
 }
 
 /// Tokenize the input expression
-/// Uses a regular expression to break down the input string into numbers, operators, parentheses, and function names
-fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
-    let rexpression = Regex::new(r"(?i)(\d+\.?\d*|\+|\-|\*|\/|\^|\(|\)|ln|log2|exp|asin|acos|atan|sin|cos|tan|abs|sqrt|log10|PI|E)")
-        .unwrap();
+/// Uses a regular expression to break down the input string into numbers, operators, parentheses, commas and names.
+/// Numbers can start with a dot (.5) and have an exponent (1e-3, 6.02E23). An E that is not followed by digits is the constant E, so 2E is 2 then E.
+/// A number followed by ° is an angle in degrees: the number then a `°` postfix operator.
+/// Integers can also be written in hexadecimal (0xFF), binary (0b101) or octal (0o17); an invalid digit or a value above 2^53 is an InvalidNumber error.
+/// Names are then classified as constants, functions (a name followed by `(`) or variables.
+/// Whitespace (spaces, tabs, new lines) separates tokens, so `si n(1)` is not `sin(1)` and `2 3` is two numbers, not 23.
+/// The whole input must be consumed: anything between two tokens other than whitespace is an unexpected character.
+/// Every token is returned with its span in the expression.
+fn tokenize(evaluator: &Evaluator, expression: &str) -> Result<Vec<(Token, Span)>, BtMathError> {
+    let rexpression = Regex::new(
+        r"(0[xXbBoO][0-9A-Za-z_]*|(?:\d+\.?\d*|\.\d+)(?:[eE][+-]?\d+)?°?|\+|\-|\*|//|\/|%|\^|<<|>>|<=|>=|==|!=|<|>|&&|\|\||&|\||~|!|\?|:|\(|\)|,|[A-Za-z_][A-Za-z0-9_]*)",
+    )
+    .unwrap();
     let mut tokens = Vec::new();
 
-    let mut iter = rexpression.captures_iter(expression);
+    let mut lexemes: Vec<(&str, Span)> = Vec::new();
+    let mut last_end = 0;
+    for m in rexpression.find_iter(expression) {
+        check_unmatched(expression, last_end, m.start())?;
+        let span = Span::new(m.start(), m.end());
+        if let Some(radix) = radix_prefix(m.as_str())
+            && !u64::from_str_radix(&m.as_str()[2..], radix).is_ok_and(|n| n <= MAX_EXACT_INTEGER)
+        {
+            return Err(BtMathError::InvalidNumber {
+                literal: m.as_str().to_owned(),
+                span,
+            });
+        }
+        lexemes.push((m.as_str(), span));
+        last_end = m.end();
+    }
+    check_unmatched(expression, last_end, expression.len())?;
+    let mut iter = lexemes.into_iter();
 
-    while let Some(cap) = iter.next() {
-        let token = &cap[0];
-        push_tokens(&mut iter, &mut tokens, token)
+    while let Some((token, span)) = iter.next() {
+        push_tokens(evaluator, &mut iter, &mut tokens, token, span)
     }
 
     Ok(tokens)
 }
 
-fn push_tokens(iter: &mut CaptureMatches<'_, '_>, tokens: &mut Vec<Token>, token: &str) {
-    if let Ok(number) = f64::from_str(token) {
-        tokens.push(Token::Number(number));
-    } else if token == "-" {
-        if let Some(cap_fwd) = iter.next() {
-            let token_fwd = &cap_fwd[0];
-                match tokens.last() {
-                    None => {
-                        if let Ok(number_fwd) = f64::from_str(token_fwd) {
-                            tokens.push(Token::Number(number_fwd * -1.00));
-                        }else if let Ok(number_fwd) = evaluate_const(&token_fwd.to_string()) {
-                            tokens.push(Token::Number(number_fwd * -1.00 ));
-                        } else {
-                            //tokens.push(Token::Operator(token.to_string()));
-                            tokens.push(Token::Number(-1.00));
-                            tokens.push(Token::Operator("*".to_owned()));                            
-                            push_tokens(iter, tokens, token_fwd); 
-                        }
-                    }
-                    Some(c) => {
-                        //if let Ok(number_prev) = f64::from_str(&c.to_string()) {
-                        if !f64::from_str(&c.to_string()).is_err() {
-                            if let Ok(number_fwd) = f64::from_str(token_fwd) {
-                                tokens.push(Token::Operator(token.to_string()));
-                                tokens.push(Token::Number(number_fwd));
-                            }else if let Ok(number_fwd) = evaluate_const(&token_fwd.to_string()) {
-                                tokens.push(Token::Number(number_fwd * -1.00 ));    
-                            }else{
-                                //tokens.push(Token::Number(-1.00));
-                                //tokens.push(Token::Operator("*".to_owned()));
-                                tokens.push(Token::Operator("-".to_owned()));
-                                push_tokens(iter, tokens, token_fwd);                            
-                            }
-                        }else if c.to_string() == "(" || is_operator(&c.to_string()) {
-                            if let Ok(number_fwd) = f64::from_str(token_fwd) {
-                                tokens.push(Token::Number(number_fwd * -1.00));
-                            }else{
-                                tokens.push(Token::Number(-1.00));
-                                tokens.push(Token::Operator("*".to_owned()));
-                                push_tokens(iter, tokens, token_fwd);   
-                            }
-                        } else {
-                            tokens.push(Token::Operator(token.to_string()));
-                            push_tokens(iter, tokens, token_fwd);
-                        }
-                    }
-            //    }
-            }
+/// Insert a `*` operator between every pair of tokens where an operand (a number, constant, variable or closing parenthesis) is followed by the start of another operand (a number, constant, variable, function or opening parenthesis).
+/// Two numbers in a row (`2 3`) are not multiplied: they stay a MissingOperator error.
+/// The inserted operator has an empty span at the start of the second operand.
+fn insert_implicit_multiplication(tokens: Vec<(Token, Span)>) -> Vec<(Token, Span)> {
+    let mut result: Vec<(Token, Span)> = Vec::with_capacity(tokens.len());
+    for (token, span) in tokens {
+        let ends_operand = result.last().is_some_and(|(last, _)| last.ends_operand());
+        let starts_operand = matches!(
+            token,
+            Token::Number(_)
+                | Token::Constant(..)
+                | Token::Variable(_)
+                | Token::Function(..)
+                | Token::LeftParen
+        );
+        let two_numbers = matches!(
+            (result.last(), &token),
+            (Some((Token::Number(_), _)), Token::Number(_))
+        );
+        if ends_operand && starts_operand && !two_numbers {
+            result.push((
+                Token::Operator("*".to_owned()),
+                Span::new(span.start, span.start),
+            ));
+        }
+        result.push((token, span));
+    }
+    result
+}
+
+/// The largest integer up to which every integer is exactly represented by an f64: 2^53
+const MAX_EXACT_INTEGER: u64 = 1 << 53;
+
+/// Returns the radix of an integer literal with a 0x, 0b or 0o prefix, or None for any other token
+fn radix_prefix(token: &str) -> Option<u32> {
+    match token.get(..2)?.to_lowercase().as_str() {
+        "0x" => Some(16),
+        "0b" => Some(2),
+        "0o" => Some(8),
+        _ => None,
+    }
+}
+
+/// Fails on the first character of expression[start..end] (text not matched by any token) that is not whitespace
+fn check_unmatched(expression: &str, start: usize, end: usize) -> Result<(), BtMathError> {
+    match expression[start..end]
+        .char_indices()
+        .find(|(_, c)| !c.is_whitespace())
+    {
+        Some((i, character)) => Err(BtMathError::UnexpectedCharacter {
+            character,
+            span: Span::new(start + i, start + i + character.len_utf8()),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn push_tokens(
+    evaluator: &Evaluator,
+    iter: &mut std::vec::IntoIter<(&str, Span)>,
+    tokens: &mut Vec<(Token, Span)>,
+    token: &str,
+    span: Span,
+) {
+    if let Some(degrees) = token.strip_suffix('°')
+        && let Some(number) = evaluator.number(degrees)
+    {
+        let end = span.start + degrees.len();
+        tokens.push((Token::Number(number), Span::new(span.start, end)));
+        tokens.push((
+            Token::PostfixOperator("°".to_owned()),
+            Span::new(end, span.end),
+        ));
+    } else if let Some(number) = evaluator.number(token) {
+        tokens.push((Token::Number(number), span));
+    } else if token == "-" || token == "+" {
+        // After an operand it is a binary operator, else it is the sign of the operand after it
+        if tokens.last().is_some_and(|(last, _)| last.ends_operand()) {
+            tokens.push((Token::Operator(token.to_string()), span));
+        } else {
+            tokens.push((Token::UnaryOperator(token.to_string()), span));
+        }
+    } else if [
+        "*", "/", "//", "%", "^", "&", "|", "<<", ">>", "<", "<=", ">", ">=", "==", "!=",
+    ]
+    .contains(&token)
+    {
+        tokens.push((Token::Operator(token.to_string()), span));
+    } else if ["xor", "and", "or"].contains(&token.to_lowercase().as_str()) {
+        tokens.push((Token::Operator(token.to_lowercase()), span));
+    } else if token == "&&" {
+        tokens.push((Token::Operator("and".to_owned()), span));
+    } else if token == "||" {
+        tokens.push((Token::Operator("or".to_owned()), span));
+    } else if token.eq_ignore_ascii_case("not") {
+        tokens.push((Token::UnaryOperator("not".to_owned()), span));
+    } else if token == "~" {
+        tokens.push((Token::UnaryOperator(token.to_string()), span));
+    } else if token == "!" {
+        // After an operand `!` is the factorial, else it is the logical not
+        if tokens.last().is_some_and(|(last, _)| last.ends_operand()) {
+            tokens.push((Token::PostfixOperator(token.to_string()), span));
         } else {
-            tokens.push(Token::Operator(token.to_string()));
+            tokens.push((Token::UnaryOperator("not".to_owned()), span));
         }
-    } else if token == "+" || token == "*" || token == "/" || token == "^" {
-        tokens.push(Token::Operator(token.to_string()));
     } else if token == "(" {
-        tokens.push(Token::LeftParen);
+        tokens.push((Token::LeftParen, span));
     } else if token == ")" {
-        tokens.push(Token::RightParen);
-    } else if let Ok(number) = evaluate_const(&token.to_string()) {
-        tokens.push(Token::Number(number));
+        tokens.push((Token::RightParen, span));
+    } else if token == "," {
+        tokens.push((Token::Comma, span));
+    } else if token == "?" {
+        tokens.push((Token::Question, span));
+    } else if token == ":" {
+        tokens.push((Token::Colon, span));
+    } else if let Some(value) = evaluator.constant(token) {
+        tokens.push((Token::Constant(token.to_uppercase(), value), span));
+    } else if matches!(iter.as_slice().first(), Some(("(", _))) {
+        tokens.push((Token::Function(token.to_lowercase(), 0), span));
     } else {
-        tokens.push(Token::Function(token.to_string()));
+        tokens.push((Token::Variable(token.to_string()), span));
     }
-    
 }
 
-fn is_operator(c: &str) -> bool {
-    c == "+" || c == "-" || c == "*" || c == "/" || c == "^"
+/// Returns true if the name can be written as a single name token: [A-Za-z_][A-Za-z0-9_]*
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
-/// Evaluate constants and returns its f64 value or same received strings as error.
-fn evaluate_const(p_const: &String) -> Result<f64, &str> {
+/// Returns true if the name is read as an operator, a number or a conditional rather than as a function, constant or variable
+fn is_keyword(name: &str) -> bool {
+    ["and", "or", "not", "xor", "if", "inf", "infinity", "nan"]
+        .contains(&name.to_lowercase().as_str())
+}
+
+/// Error for a function or a constant registered with a name that cannot be used
+fn invalid_name(name: &str) -> BtMathError {
+    BtMathError::InvalidName {
+        name: name.to_owned(),
+        span: Span::new(0, name.len()),
+    }
+}
+
+/// Evaluate built-in constants and returns its f64 value or same received strings as error.
+fn evaluate_const(p_const: &str) -> Result<f64, &str> {
     match p_const.to_uppercase().as_str() {
-        "PI" => return Ok(std::f64::consts::PI),
-        "E" => return Ok(std::f64::consts::E),
-        _ => return Err(p_const),
-    };
+        "PI" => Ok(std::f64::consts::PI),
+        "E" => Ok(std::f64::consts::E),
+        _ => Err(p_const),
+    }
 }
 
 /// Convert infix notation to Reverse Polish Notation (RPN) using the Shunting Yard algorithm
 /// It uses a stack to temporarily hold operators until they can be placed behind their operands according to their precedence.
-fn to_rpn(tokens: &[Token]) -> Result<Vec<Token>, String> {
+/// A `)` without a `(` before it, or a `(` never closed, is reported with the span of that parenthesis.
+/// A function is output when its `)` is found, with the number of arguments (comma separated) of the call.
+fn to_rpn(tokens: &[(Token, Span)]) -> Result<Vec<(Token, Span)>, BtMathError> {
     let mut output = Vec::new();
-    let mut operators = VecDeque::new();
+    let mut operators: VecDeque<(Token, Span)> = VecDeque::new();
+    // For every open parenthesis: the number of commas found so far if it opens the arguments of a function
+    let mut calls: Vec<Option<usize>> = Vec::new();
 
-    for token in tokens {
+    for (i, (token, span)) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|p| &tokens[p].0);
+        let next = tokens.get(i + 1).map(|(next, _)| next);
         match token {
-            Token::Number(_) => output.push(token.clone()),
-            //Token::Function(_) => operators.push_back(token.clone()),
-            Token::LeftParen => operators.push_back(Token::LeftParen),
+            Token::Number(_) | Token::Constant(..) | Token::Variable(_) => {
+                output.push((token.clone(), *span))
+            }
+            Token::Function(..) => {
+                if !matches!(next, Some(Token::LeftParen)) {
+                    return Err(BtMathError::MissingOperand { span: *span });
+                }
+                operators.push_back((token.clone(), *span));
+            }
+            Token::LeftParen => {
+                calls.push(match previous {
+                    Some(Token::Function(..)) => Some(0),
+                    _ => None,
+                });
+                operators.push_back((Token::LeftParen, *span));
+            }
+            Token::Comma => {
+                pop_until_left_paren(&mut operators, &mut output);
+                match calls.last_mut() {
+                    Some(Some(commas)) => {
+                        if matches!(previous, Some(Token::LeftParen | Token::Comma)) {
+                            return Err(BtMathError::MissingOperand { span: *span });
+                        }
+                        *commas += 1;
+                    }
+                    // A comma outside the arguments of a function separates nothing
+                    _ => {
+                        return Err(BtMathError::UnexpectedCharacter {
+                            character: ',',
+                            span: *span,
+                        });
+                    }
+                }
+            }
             Token::RightParen => {
-                while let Some(op) = operators.pop_back() {
-                    match op {
-                        Token::LeftParen => break,
-                        _ => output.push(op),
+                if !pop_until_left_paren(&mut operators, &mut output) {
+                    return Err(BtMathError::UnbalancedParens {
+                        paren: ')',
+                        span: *span,
+                    });
+                }
+                operators.pop_back();
+                if let Some(Some(commas)) = calls.pop() {
+                    let args = match previous {
+                        Some(Token::LeftParen) => 0,
+                        Some(Token::Comma) => {
+                            return Err(BtMathError::MissingOperand { span: *span });
+                        }
+                        _ => commas + 1,
+                    };
+                    // The function is right below its `(`; it spans up to this parenthesis
+                    if let Some((Token::Function(name, _), func_span)) = operators.pop_back() {
+                        output.push((Token::Function(name, args), func_span.merge(*span)));
                     }
                 }
             }
-            Token::Operator(_) | Token::Function(_) => {
-                while let Some(op) = operators.back() {
-                    let _p_token = Token::Operator("^".to_string());
-                    if matches!(op, Token::Operator(_) | Token::Function(_))
+            // The condition is complete: every operator binds tighter than a conditional.
+            // A conditional before it is not popped, so a ? b : c ? d : e is a ? b : (c ? d : e)
+            Token::Question => {
+                while let Some((Token::Operator(_) | Token::UnaryOperator(_), _)) = operators.back()
+                {
+                    output.push(operators.pop_back().unwrap());
+                }
+                operators.push_back((Token::Question, *span));
+            }
+            // The first branch is complete up to its `?`, which now waits for the second branch
+            Token::Colon => {
+                while let Some((Token::Operator(_) | Token::UnaryOperator(_) | Token::Colon, _)) =
+                    operators.back()
+                {
+                    output.push(operators.pop_back().unwrap());
+                }
+                match operators.back_mut() {
+                    Some((op @ Token::Question, _)) => *op = Token::Colon,
+                    _ => {
+                        return Err(BtMathError::UnexpectedCharacter {
+                            character: ':',
+                            span: *span,
+                        });
+                    }
+                }
+            }
+            // A prefix operator applies to the operand after it, there is nothing to pop yet
+            Token::UnaryOperator(_) => operators.push_back((token.clone(), *span)),
+            // A postfix operator binds tighter than any other operator: its operand is already complete in the output
+            Token::PostfixOperator(_) => output.push((token.clone(), *span)),
+            Token::Operator(_) => {
+                while let Some((op, _)) = operators.back() {
+                    if matches!(op, Token::Operator(_) | Token::UnaryOperator(_))
                         && (op.precedence() > token.precedence()
-                            || (op.precedence() == token.precedence() && matches!(token, _p_token)))
+                            || (op.precedence() == token.precedence()
+                                && !token.is_right_associative()))
                     {
                         output.push(operators.pop_back().unwrap());
                     } else {
                         break;
                     }
                 }
-                operators.push_back(token.clone());
+                operators.push_back((token.clone(), *span));
             }
         }
     }
 
     while let Some(op) = operators.pop_back() {
+        if let (Token::LeftParen, span) = op {
+            return Err(BtMathError::UnbalancedParens { paren: '(', span });
+        }
         output.push(op);
     }
 
     Ok(output)
 }
 
-/// Evaluate the expression in Reverse Polish Notation (RPN)
-/// Numbers are pushed onto the stack, and when an operator is encountered, it pops two numbers from the stack, applies the operation, and pushes the result back onto the stack. Functions also pop arguments from the stack and apply mathematical operations accordingly.
-fn evaluate_rpn(rpn: &[Token]) -> Result<f64, String> {
-    let mut stack = VecDeque::new();
-    for token in rpn {
+/// Move the operators above the innermost `(` to the output, leaving the `(` on the stack.
+/// Returns false if there is no `(` on the stack.
+fn pop_until_left_paren(
+    operators: &mut VecDeque<(Token, Span)>,
+    output: &mut Vec<(Token, Span)>,
+) -> bool {
+    while let Some((op, _)) = operators.back() {
+        if matches!(op, Token::LeftParen) {
+            return true;
+        }
+        output.push(operators.pop_back().unwrap());
+    }
+    false
+}
+
+/// Pop the condition and the two branches of a conditional from the stack and push the Conditional node.
+/// `span` is the span of its `?` or of the if(...) call; the node spans its three parts as well.
+fn push_conditional(stack: &mut Vec<Expr>, span: Span) -> Result<(), BtMathError> {
+    let otherwise = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+    let then = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+    let condition = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+    let span = span.merge(condition.span).merge(otherwise.span);
+    stack.push(Expr::new(
+        ExprKind::Conditional {
+            condition: Box::new(condition),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        },
+        span,
+    ));
+    Ok(())
+}
+
+/// Build the expression tree from the expression in Reverse Polish Notation (RPN)
+/// Numbers, constants and variables are pushed onto the stack, and when an operator is encountered, it pops two sub-expressions from the stack, combines them into a new node, and pushes the node back onto the stack. Functions also pop their arguments from the stack.
+/// Functions are checked to exist and to receive the number of arguments they expect.
+/// `expression_span` is the span of the whole expression, used when it has no value at all.
+fn build_ast(
+    evaluator: &Evaluator,
+    rpn: &[(Token, Span)],
+    expression_span: Span,
+) -> Result<Expr, BtMathError> {
+    let mut stack: Vec<Expr> = Vec::new();
+    for (token, span) in rpn {
+        let span = *span;
         match token {
             Token::Number(value) => {
-                stack.push_back(*value);
+                stack.push(Expr::new(ExprKind::Number(*value), span));
+            }
+            Token::Constant(name, value) => {
+                stack.push(Expr::new(
+                    ExprKind::Constant {
+                        name: name.clone(),
+                        value: *value,
+                    },
+                    span,
+                ));
+            }
+            Token::Variable(name) => {
+                stack.push(Expr::new(ExprKind::Variable(name.clone()), span));
+            }
+            Token::UnaryOperator(symbol) | Token::PostfixOperator(symbol) => {
+                let operand = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+                let op = match token {
+                    Token::PostfixOperator(_) => UnaryOp::from_postfix_symbol(symbol),
+                    _ => UnaryOp::from_symbol(symbol),
+                }
+                .ok_or_else(|| BtMathError::UnknownOperator {
+                    symbol: symbol.clone(),
+                    span,
+                })?;
+                let node_span = span.merge(operand.span);
+                stack.push(Expr::new(
+                    ExprKind::Unary {
+                        op,
+                        operand: Box::new(operand),
+                    },
+                    node_span,
+                ));
             }
             Token::Operator(op) => {
-                let b = stack
-                    .pop_back()
-                    .ok_or("Invalid expression: not enough values for operator (b)")?;
-                let a = stack
-                    .pop_back()
-                    .ok_or("Invalid expression: not enough values for operator (a)")?;
-                let result = match op.as_str() {
-                    "+" => a + b,
-                    "-" => a - b,
-                    "*" => a * b,
-                    "/" => a / b,
-                    "^" => a.powf(b),
-                    _ => return Err(format!("Unknown operator {:?}", token)), // panic!("Unknown operator"),
-                };
-                stack.push_back(result);
-            }
-            Token::Function(func) => {
-                let arg = stack
-                    .pop_back()
-                    .ok_or("Invalid expression: not enough values for function")?;
-                let result = match func.to_lowercase().as_str() {
-                    "sin" => arg.sin(),
-                    "cos" => arg.cos(),
-                    "tan" => arg.tan(),
-                    "asin" => arg.asin(),
-                    "acos" => arg.acos(),
-                    "atan" => arg.atan(),
-                    "exp" => arg.exp(),
-                    "ln" => arg.ln(),
-                    "log" => arg.log10(),
-                    "log2" => arg.log2(),
-                    "abs" => arg.abs(),
-                    "sqrt" => arg.sqrt(),
-                    "log10" => arg.log10(),
-                    _ => return Err(format!("Unknown function: {:?}", token)), //panic!("Unknown function"),
-                };
-                stack.push_back(result);
-            }
-            _ => return Err(format!("Invalid token: {:?}", token)),
-        }
-    }
-
-    stack
-        .pop_back()
-        .ok_or("Invalid expression: no result on stack".to_owned())
+                let rhs = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+                let lhs = stack.pop().ok_or(BtMathError::MissingOperand { span })?;
+                let op = BinaryOp::from_symbol(op).ok_or_else(|| BtMathError::UnknownOperator {
+                    symbol: op.clone(),
+                    span,
+                })?;
+                let node_span = lhs.span.merge(rhs.span).merge(span);
+                stack.push(Expr::new(
+                    ExprKind::Binary {
+                        op,
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    },
+                    node_span,
+                ));
+            }
+            // if(condition, then, otherwise) is not a function: its branches are evaluated only when chosen
+            Token::Function(func, args) if func == "if" => {
+                if *args != 3 {
+                    return Err(BtMathError::ArityMismatch {
+                        name: func.clone(),
+                        expected: 3,
+                        found: *args,
+                        span: Span::new(span.start, span.start + func.len()),
+                    });
+                }
+                push_conditional(&mut stack, span)?;
+            }
+            Token::Colon => push_conditional(&mut stack, span)?,
+            // A `?` without its `:`
+            Token::Question => {
+                return Err(BtMathError::UnexpectedCharacter {
+                    character: '?',
+                    span,
+                });
+            }
+            Token::Function(func, args) => {
+                let name_span = Span::new(span.start, span.start + func.len());
+                evaluator.function_for_call(func, *args, name_span)?;
+                let first = stack
+                    .len()
+                    .checked_sub(*args)
+                    .ok_or(BtMathError::MissingOperand { span })?;
+                let args = stack.split_off(first);
+                stack.push(Expr::new(
+                    ExprKind::Function {
+                        name: func.clone(),
+                        args,
+                    },
+                    span,
+                ));
+            }
+            // to_rpn reports unbalanced parentheses, none are left in the RPN expression
+            _ => return Err(BtMathError::UnbalancedParens { paren: '(', span }),
+        }
+    }
+
+    if stack.len() > 1 {
+        // The second value is the first one that is not combined with the values before it
+        return Err(BtMathError::MissingOperator {
+            span: stack[1].span,
+        });
+    }
+    stack.pop().ok_or(BtMathError::EmptyExpression {
+        span: expression_span,
+    })
+}
+
+/// Function is a mathematical function known by an Evaluator: the numbers of arguments it accepts and its implementation.
+#[derive(Debug, Clone)]
+struct Function {
+    arity: RangeInclusive<usize>,
+    implementation: Implementation,
+}
+
+/// Enum Implementation is the code of a function, which receives the evaluated arguments:
+/// Builtin is a function provided by this library.
+/// Custom is a function registered on an Evaluator.
+/// AngleInput is a built-in trigonometric function of an angle given in the unit of the AngleMode.
+/// AngleOutput is a built-in inverse trigonometric function returning an angle in the unit of the AngleMode.
+/// Round is the built-in round(x) or round(x, digits) function, rounding halves with the RoundingMode.
+#[derive(Clone)]
+enum Implementation {
+    Builtin(BuiltinFunction),
+    Custom(CustomFunction),
+    AngleInput(fn(f64) -> f64, AngleMode),
+    AngleOutput(BuiltinFunction, AngleMode),
+    Round(RoundingMode),
+}
+
+/// A function provided by this library. It fails with the argument outside of its domain, e.g., sqrt(-1)
+type BuiltinFunction = fn(&[f64]) -> Result<f64, f64>;
+
+/// A function registered on an Evaluator
+type CustomFunction = Arc<dyn Fn(&[f64]) -> f64 + Send + Sync>;
+
+impl Implementation {
+    /// Calls the function with its arguments
+    /// Fails with the argument a built-in function is not defined for. A registered function cannot fail.
+    fn call(&self, args: &[f64]) -> Result<f64, f64> {
+        match self {
+            Implementation::Builtin(function) => function(args),
+            Implementation::Custom(function) => Ok(function(args)),
+            Implementation::AngleInput(function, mode) => Ok(function(mode.to_radians(args[0]))),
+            Implementation::AngleOutput(function, mode) => {
+                function(args).map(|angle| mode.radians_to_unit(angle))
+            }
+            Implementation::Round(mode) => Ok(match args.get(1) {
+                Some(digits) if digits.is_nan() => f64::NAN,
+                // Digits are truncated to an integer; negative digits round to tens, hundreds...
+                Some(digits) => {
+                    let digits = digits.trunc() as i64;
+                    let scaled = shift_decimal(args[0], digits);
+                    if scaled.is_finite() {
+                        shift_decimal(mode.round(scaled), digits.saturating_neg())
+                    } else {
+                        // More digits than an f64 holds: there is nothing to round
+                        args[0]
+                    }
+                }
+                None => mode.round(args[0]),
+            }),
+        }
+    }
+}
+
+/// Returns value * 10^digits, computed on the shortest decimal form of the value (the one it is written with) rather than
+/// on its binary form: 1.005 shifted by 2 digits is 100.5, not 100.49999999999999, so round(1.005, 2) is 1.01
+fn shift_decimal(value: f64, digits: i64) -> f64 {
+    if !value.is_finite() {
+        return value;
+    }
+    let decimal = format!("{:e}", value);
+    let (mantissa, exponent) = decimal.split_once('e').unwrap_or((&decimal, "0"));
+    let exponent = exponent.parse::<i64>().unwrap_or(0).saturating_add(digits);
+    f64::from_str(&format!("{}e{}", mantissa, exponent)).unwrap_or(f64::NAN)
+}
+
+/// Implementing Debug trait for Implementation. Closures cannot be printed so only the kind is shown
+impl fmt::Debug for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Implementation::Builtin(_)
+            | Implementation::AngleInput(..)
+            | Implementation::AngleOutput(..)
+            | Implementation::Round(_) => write!(f, "Builtin"),
+            Implementation::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Returns a built-in mathematical function by its name, or None if there is no such function
+/// The trigonometric functions use angles in the unit of `angle_mode` and round uses `rounding_mode`.
+fn builtin_function(
+    func: &str,
+    angle_mode: AngleMode,
+    rounding_mode: RoundingMode,
+) -> Option<Function> {
+    let name = func.to_lowercase();
+    if name == "round" {
+        return Some(Function {
+            arity: 1..=2,
+            implementation: Implementation::Round(rounding_mode),
+        });
+    }
+    // Aggregate functions accept any number of arguments, at least one
+    let aggregate: Option<BuiltinFunction> = match name.as_str() {
+        "sum" => Some(|a| Ok(a.iter().sum())),
+        "product" => Some(|a| Ok(a.iter().product())),
+        "avg" => Some(|a| Ok(mean(a))),
+        "min" => Some(|a| Ok(a.iter().copied().fold(f64::INFINITY, f64::min))),
+        "max" => Some(|a| Ok(a.iter().copied().fold(f64::NEG_INFINITY, f64::max))),
+        "median" => Some(|a| Ok(median(a))),
+        // Population variance and standard deviation (divided by the number of values)
+        "variance" => Some(|a| Ok(variance(a))),
+        "stddev" => Some(|a| Ok(variance(a).sqrt())),
+        _ => None,
+    };
+    if let Some(function) = aggregate {
+        return Some(Function {
+            arity: 1..=usize::MAX,
+            implementation: Implementation::Builtin(function),
+        });
+    }
+    let angle_input: Option<fn(f64) -> f64> = match name.as_str() {
+        "sin" => Some(f64::sin),
+        "cos" => Some(f64::cos),
+        "tan" => Some(f64::tan),
+        "sec" => Some(|x| 1.0 / x.cos()),
+        "csc" => Some(|x| 1.0 / x.sin()),
+        "cot" => Some(|x| 1.0 / x.tan()),
+        _ => None,
+    };
+    if let Some(function) = angle_input {
+        return Some(Function {
+            arity: 1..=1,
+            implementation: Implementation::AngleInput(function, angle_mode),
+        });
+    }
+    let angle_output: Option<(usize, BuiltinFunction)> = match name.as_str() {
+        "asin" => Some((1, |a| in_domain(a[0], a[0].abs() <= 1.0).map(f64::asin))),
+        "acos" => Some((1, |a| in_domain(a[0], a[0].abs() <= 1.0).map(f64::acos))),
+        "atan" => Some((1, |a| Ok(a[0].atan()))),
+        "asec" => Some((1, |a| {
+            in_domain(a[0], a[0].abs() >= 1.0).map(|x| (1.0 / x).acos())
+        })),
+        "acsc" => Some((1, |a| {
+            in_domain(a[0], a[0].abs() >= 1.0).map(|x| (1.0 / x).asin())
+        })),
+        // acot(x) = atan(1/x), between -PI/2 and PI/2
+        "acot" => Some((1, |a| Ok((1.0 / a[0]).atan()))),
+        // atan2(y, x) is the angle of the point (x, y)
+        "atan2" => Some((2, |a| Ok(a[0].atan2(a[1])))),
+        _ => None,
+    };
+    if let Some((arity, function)) = angle_output {
+        return Some(Function {
+            arity: arity..=arity,
+            implementation: Implementation::AngleOutput(function, angle_mode),
+        });
+    }
+
+    let (arity, function): (usize, BuiltinFunction) = match name.as_str() {
+        "sinh" => (1, |a| Ok(a[0].sinh())),
+        "cosh" => (1, |a| Ok(a[0].cosh())),
+        "tanh" => (1, |a| Ok(a[0].tanh())),
+        "asinh" => (1, |a| Ok(a[0].asinh())),
+        "acosh" => (1, |a| in_domain(a[0], a[0] >= 1.0).map(f64::acosh)),
+        "atanh" => (1, |a| in_domain(a[0], a[0].abs() < 1.0).map(f64::atanh)),
+        "exp" => (1, |a| Ok(a[0].exp())),
+        "ln" => (1, |a| in_domain(a[0], a[0] > 0.0).map(f64::ln)),
+        "log2" => (1, |a| in_domain(a[0], a[0] > 0.0).map(f64::log2)),
+        "abs" => (1, |a| Ok(a[0].abs())),
+        "sqrt" => (1, |a| in_domain(a[0], a[0] >= 0.0).map(f64::sqrt)),
+        "log10" => (1, |a| in_domain(a[0], a[0] > 0.0).map(f64::log10)),
+        // pow(x, y) = x^y
+        "pow" => (2, |a| Ok(a[0].powf(a[1]))),
+        "hypot" => (2, |a| Ok(a[0].hypot(a[1]))),
+        // log(base, x) is the logarithm of x in the given base, a positive number other than 1
+        "log" => (2, |a| {
+            let base = in_domain(a[0], a[0] > 0.0 && a[0] != 1.0)?;
+            Ok(in_domain(a[1], a[1] > 0.0)?.log(base))
+        }),
+        // clamp(x, min, max). Unlike f64::clamp it does not panic when min > max: the result is max
+        "clamp" => (3, |a| Ok(a[0].max(a[1]).min(a[2]))),
+        // mod(a, b) is the Euclidean remainder: never negative, unlike the % operator
+        "mod" => (2, |a| Ok(a[0].rem_euclid(in_domain(a[1], a[1] != 0.0)?))),
+        // deg(x) converts x radians to degrees and rad(x) converts x degrees to radians, whatever the angle mode
+        "deg" => (1, |a| Ok(a[0].to_degrees())),
+        "rad" => (1, |a| Ok(a[0].to_radians())),
+        // gamma(x) = (x - 1)! for positive integers. It is not defined for 0 and the negative integers
+        "gamma" => (1, |a| {
+            in_domain(a[0], a[0] > 0.0 || a[0].fract() != 0.0).map(gamma)
+        }),
+        "floor" => (1, |a| Ok(a[0].floor())),
+        "ceil" => (1, |a| Ok(a[0].ceil())),
+        "trunc" => (1, |a| Ok(a[0].trunc())),
+        // sign(x) is -1, 0 or 1. Unlike f64::signum, sign(0) is 0
+        "sign" => (1, |a| Ok(if a[0] == 0.0 { a[0] } else { a[0].signum() })),
+        // frac(x) = x - trunc(x), with the sign of x: frac(-1.25) = -0.25
+        "frac" => (1, |a| Ok(a[0].fract())),
+        _ => return None,
+    };
+    Some(Function {
+        arity: arity..=arity,
+        implementation: Implementation::Builtin(function),
+    })
+}
+
+/// Returns the argument of a function if `valid` (it is in the domain of the function), or else fails with it.
+/// NaN is accepted by every function: it is the result whatever the domain.
+fn in_domain(value: f64, valid: bool) -> Result<f64, f64> {
+    if valid || value.is_nan() {
+        Ok(value)
+    } else {
+        Err(value)
+    }
+}
+
+/// Gamma function: gamma(n) = (n - 1)! for positive integers, computed as an exact product up to 171 (the largest
+/// factorial an f64 can hold). Other values use the Lanczos approximation (g = 7, n = 9), with the reflection formula
+/// below 0.5. It is NaN at 0 and the negative integers, where it is not defined.
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x.fract() == 0.0 {
+        return match x {
+            x if x <= 0.0 => f64::NAN,
+            x if x > 171.0 => f64::INFINITY,
+            x => (2..x as u32).fold(1.0, |product, n| product * n as f64),
+        };
+    }
+    if x < 0.5 {
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+    if x > 171.7 {
+        return f64::INFINITY;
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+/// Returns the arithmetic mean of the values
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Returns the middle value once sorted, or the mean of the two middle values for an even number of values
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+/// Returns the population variance of the values: the mean of the squared deviations from their mean
+fn variance(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
 }
@@ -1,4 +1,9 @@
-use bt_math::evaluate_expression;
+use bt_math::{AngleMode, BinaryOp, BtMathError, Context, Evaluator, Expr, ExprKind, RoundingMode, Span, compile, evaluate_expression, evaluate_with, parse};
+
+/// Returns true if two results are equal up to floating point rounding errors
+fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-12
+}
 
 #[test]
 fn test_basic_arithmetic(){
@@ -91,8 +96,7 @@ fn test_evaluate_arcs() {
 #[test]
 fn test_evaluate_invalid_funct() {
     let expression = "wxyz(-0.98803162)";
-    let expected_result = -0.98803162;
-    assert_eq!(evaluate_expression(expression).unwrap(), expected_result);    
+    assert!(evaluate_expression(expression).is_err());
 }
 
 #[test]
@@ -119,7 +123,7 @@ fn test_negative_func_start() {
 #[test]
 fn test_negative_const() {
     let expression = "-e^2*-PI";
-    let expected_result = -23.213404357363384;
+    let expected_result = 23.213404357363384;
     assert_eq!(evaluate_expression(expression).unwrap(), expected_result );
 }
 
@@ -142,4 +146,804 @@ fn test_negative_functions() {
     let expression = "-sin(45)--cos(45)-tan(-30)";
     let expected_result = -6.730912732362665;
     assert_eq!(evaluate_expression(expression).unwrap(), expected_result );
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_tree() {
+    let expected_result = Expr::new(
+        ExprKind::Binary {
+            op: BinaryOp::Add,
+            lhs: Box::new(Expr::new(ExprKind::Number(2.0), Span::new(0, 1))),
+            rhs: Box::new(Expr::new(
+                ExprKind::Function {
+                    name: "sin".to_owned(),
+                    args: vec![Expr::new(
+                        ExprKind::Constant { name: "PI".to_owned(), value: std::f64::consts::PI },
+                        Span::new(8, 10),
+                    )],
+                },
+                Span::new(4, 11),
+            )),
+        },
+        Span::new(0, 11),
+    );
+    assert_eq!(parse("2 + SIN(pi)").unwrap(), expected_result);
+}
+
+#[test]
+fn test_parse_eval_display() {
+    let expr = parse("(1 + 2) * sqrt(16)").unwrap();
+    assert_eq!(expr.to_string(), "((1 + 2) * sqrt(16))");
+    assert_eq!(expr.eval().unwrap(), 12.0);
+    assert_eq!(parse(&expr.to_string()).unwrap().to_string(), expr.to_string());
+}
+
+#[test]
+fn test_subtract_const() {
+    let expression = "3 - PI";
+    let expected_result = 3.0 - std::f64::consts::PI;
+    assert_eq!(evaluate_expression(expression).unwrap(), expected_result );
+}
+
+#[test]
+fn test_compiled_expression() {
+    let expression = "-Sin(3.2547) - pow(5.365, 3.753) * COS(-45)";
+    let compiled = compile(expression).unwrap();
+    let mut ctx = Context::new();
+    for _ in 0..3 {
+        assert_eq!(compiled.eval(&mut ctx).unwrap(), evaluate_expression(expression).unwrap());
+    }
+}
+
+#[test]
+fn test_compile_invalid_expression() {
+    let expression = "2 + * 3";
+    assert!(compile(expression).is_err());
+}
+
+#[test]
+fn test_evaluate_with_variables() {
+    let mut ctx = Context::new();
+    ctx.set("x", 3.0);
+    ctx.set("rate_2", 0.5);
+    assert_eq!(evaluate_with("x * 2 + rate_2", &ctx).unwrap(), 6.5);
+}
+
+#[test]
+fn test_unknown_variable() {
+    let mut ctx = Context::new();
+    ctx.set("x", 3.0);
+    let err = evaluate_with("x * y", &ctx).unwrap_err();
+    assert_eq!(err, BtMathError::UnknownSymbol { name: "y".to_owned(), span: Span::new(4, 5) });
+    assert_eq!(err.to_string(), "unknown variable `y` at position 4");
+}
+
+#[test]
+fn test_compiled_expression_variables() {
+    let compiled = compile("price * qty - 1").unwrap();
+    let mut ctx = Context::new();
+    ctx.set("qty", 4.0);
+    for price in [1.0, 2.5, 10.0] {
+        ctx.set("price", price);
+        assert_eq!(compiled.eval(&mut ctx).unwrap(), price * 4.0 - 1.0);
+    }
+}
+
+#[test]
+fn test_error_kinds() {
+    assert_eq!(evaluate_expression("(2 + 3").unwrap_err(), BtMathError::UnbalancedParens { paren: '(', span: Span::new(0, 1) });
+    assert_eq!(evaluate_expression("2 +").unwrap_err(), BtMathError::MissingOperand { span: Span::new(2, 3) });
+    assert_eq!(evaluate_expression("").unwrap_err(), BtMathError::EmptyExpression { span: Span::new(0, 0) });
+}
+
+#[test]
+fn test_error_is_std_error() {
+    let err: Box<dyn std::error::Error> = Box::new(evaluate_expression("2 +").unwrap_err());
+    assert_eq!(err.to_string(), "missing operand at position 2");
+}
+
+#[test]
+fn test_error_span_in_original_input() {
+    let expression = "pow(2, 3) +   rate * 2";
+    let err = evaluate_expression(expression).unwrap_err();
+    assert_eq!(err.span(), Span::new(14, 18));
+    assert_eq!(&expression[err.span().start..err.span().end], "rate");
+}
+
+#[test]
+fn test_compiled_error_span() {
+    let compiled = compile("1 +  rate").unwrap();
+    let err = compiled.eval(&mut Context::new()).unwrap_err();
+    assert_eq!(err.span(), Span::new(5, 9));
+}
+
+#[test]
+fn test_unexpected_character() {
+    let err = evaluate_expression("2 $ 3").unwrap_err();
+    assert_eq!(err, BtMathError::UnexpectedCharacter { character: '$', span: Span::new(2, 3) });
+    assert_eq!(err.to_string(), "unexpected character `$` at position 2");
+}
+
+#[test]
+fn test_unexpected_trailing_character() {
+    let err = evaluate_expression("sqrt(4) + 1.5é").unwrap_err();
+    assert_eq!(err, BtMathError::UnexpectedCharacter { character: 'é', span: Span::new(13, 15) });
+}
+
+#[test]
+fn test_unclosed_paren() {
+    let err = evaluate_expression("2 * ((1 + 3) - 4").unwrap_err();
+    assert_eq!(err, BtMathError::UnbalancedParens { paren: '(', span: Span::new(4, 5) });
+    assert_eq!(err.to_string(), "unclosed `(` at position 4");
+}
+
+#[test]
+fn test_stray_close_paren() {
+    let err = evaluate_expression("atan(1) * acos(-0.988031))").unwrap_err();
+    assert_eq!(err, BtMathError::UnbalancedParens { paren: ')', span: Span::new(25, 26) });
+    assert_eq!(err.to_string(), "unmatched `)` at position 25");
+}
+
+#[test]
+fn test_pow_with_expressions() {
+    let mut ctx = Context::new();
+    ctx.set("x", 2.0);
+    assert_eq!(evaluate_with("pow(x + 1, 2)", &ctx).unwrap(), 9.0);
+    assert_eq!(evaluate_expression("pow(sin(1), 2) + pow(cos(1), 2)").unwrap(), 1.0);
+}
+
+#[test]
+fn test_multi_argument_functions() {
+    assert_eq!(evaluate_expression("atan2(1, 1)").unwrap(), std::f64::consts::FRAC_PI_4);
+    assert_eq!(evaluate_expression("hypot(3, 4)").unwrap(), 5.0);
+    assert_eq!(evaluate_expression("log(2, 8)").unwrap(), 3.0);
+    assert_eq!(evaluate_expression("min(3, -4) + max(3, -4)").unwrap(), -1.0);
+    assert_eq!(evaluate_expression("clamp(7, 0, 5) + clamp(-1, 0, 5)").unwrap(), 5.0);
+}
+
+#[test]
+fn test_function_arity() {
+    let err = evaluate_expression("2 * pow(3)").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::ArityMismatch { name: "pow".to_owned(), expected: 2, found: 1, span: Span::new(4, 7) }
+    );
+    let err = evaluate_expression("sqrt(4, 2)").unwrap_err();
+    assert!(matches!(err, BtMathError::ArityMismatch { expected: 1, found: 2, .. }));
+}
+
+#[test]
+fn test_invalid_argument_lists() {
+    assert!(matches!(evaluate_expression("max(1, )").unwrap_err(), BtMathError::MissingOperand { .. }));
+    assert!(matches!(evaluate_expression("max(, 1)").unwrap_err(), BtMathError::MissingOperand { .. }));
+    assert_eq!(
+        evaluate_expression("1, 2").unwrap_err(),
+        BtMathError::UnexpectedCharacter { character: ',', span: Span::new(1, 2) }
+    );
+}
+
+#[test]
+fn test_unknown_function() {
+    let err = evaluate_expression("1 + wxyz(-0.98803162)").unwrap_err();
+    assert_eq!(err, BtMathError::UnknownFunction { name: "wxyz".to_owned(), span: Span::new(4, 8) });
+}
+
+#[test]
+fn test_register_function() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("lerp", 3, |a| a[0] + (a[1] - a[0]) * a[2]).unwrap();
+    evaluator.register_function("Sigmoid", 1, |a| 1.0 / (1.0 + (-a[0]).exp())).unwrap();
+    assert_eq!(evaluator.evaluate("lerp(10, 20, 0.25) * 2").unwrap(), 25.0);
+    assert_eq!(evaluator.evaluate("-SIGMOID(0) + lerp(0, sigmoid(0), 2)").unwrap(), 0.5);
+
+    let mut ctx = Context::new();
+    ctx.set("t", 0.5);
+    let compiled = evaluator.compile("lerp(pow(2, 2), 8, t)").unwrap();
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 6.0);
+    assert_eq!(evaluator.evaluate_with("lerp(0, 2, t)", &ctx).unwrap(), 1.0);
+
+    assert!(evaluate_expression("lerp(10, 20, 0.25)").is_err());
+}
+
+#[test]
+fn test_register_function_arity() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("lerp", 3, |a| a[0] + (a[1] - a[0]) * a[2]).unwrap();
+    let err = evaluator.evaluate("lerp(1, 2)").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::ArityMismatch { name: "lerp".to_owned(), expected: 3, found: 2, span: Span::new(0, 4) }
+    );
+}
+
+#[test]
+fn test_function_names_as_variables() {
+    // A name is a function only when it is followed by `(`
+    let mut ctx = Context::new();
+    ctx.set("min", 1.0);
+    ctx.set("sum", 10.0);
+    assert_eq!(evaluate_with("min + 1", &ctx).unwrap(), 2.0);
+    assert_eq!(evaluate_with("min(min, sum) * sum", &ctx).unwrap(), 10.0);
+    assert_eq!(compile("sum / 2").unwrap().eval(&mut ctx).unwrap(), 5.0);
+    assert_eq!(
+        evaluate_expression("sin + 1").unwrap_err(),
+        BtMathError::UnknownSymbol { name: "sin".to_owned(), span: Span::new(0, 3) }
+    );
+}
+
+#[test]
+fn test_register_function_invalid_name() {
+    let mut evaluator = Evaluator::new();
+    let err = evaluator.register_function("if", 1, |a| a[0]).unwrap_err();
+    assert_eq!(err, BtMathError::InvalidName { name: "if".to_owned(), span: Span::new(0, 2) });
+    assert_eq!(err.to_string(), "`if` cannot be used as a name");
+    for name in ["NOT", "xor", "nan", "e", "Pi", "my func", "2x", ""] {
+        assert!(matches!(evaluator.register_function(name, 1, |a| a[0]), Err(BtMathError::InvalidName { .. })), "{}", name);
+    }
+    evaluator.register_constant("G", 9.81).unwrap();
+    assert!(evaluator.register_function("g", 1, |a| a[0]).is_err());
+    // A built-in function can still be replaced
+    evaluator.register_function("_sqrt", 1, |a| a[0].sqrt()).unwrap();
+    evaluator.register_function("sqrt", 1, |a| a[0] * a[0]).unwrap();
+    assert_eq!(evaluator.evaluate("sqrt(3) + _sqrt(4)").unwrap(), 11.0);
+}
+
+#[test]
+fn test_register_constant() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_constant("TAX_RATE", 0.21).unwrap();
+    evaluator.register_constant("g", 9.81).unwrap();
+    assert_eq!(evaluator.evaluate("100 * tax_rate + G - G").unwrap(), 21.0);
+    assert_eq!(evaluator.evaluate("-G * 2").unwrap(), -19.62);
+
+    // Constants are resolved when the expression is parsed
+    let expr = evaluator.parse("TAX_RATE * 2").unwrap();
+    evaluator.register_constant("TAX_RATE", 0.1).unwrap();
+    assert_eq!(expr.eval().unwrap(), 0.42);
+    assert_eq!(evaluator.evaluate("TAX_RATE * 2").unwrap(), 0.2);
+}
+
+#[test]
+fn test_register_constant_invalid_name() {
+    let mut evaluator = Evaluator::new();
+    let err = evaluator.register_constant("TAX RATE", 0.21).unwrap_err();
+    assert_eq!(err, BtMathError::InvalidName { name: "TAX RATE".to_owned(), span: Span::new(0, 8) });
+    for name in ["sin", "and", "Xor", "not", "if", "1G", "G-2"] {
+        assert!(matches!(evaluator.register_constant(name, 3.0), Err(BtMathError::InvalidName { .. })), "{}", name);
+    }
+    evaluator.register_function("lerp", 3, |a| a[0] + (a[1] - a[0]) * a[2]).unwrap();
+    assert!(evaluator.register_constant("LERP", 1.0).is_err());
+    assert_eq!(evaluator.evaluate("sin(0) + lerp(0, 2, 0.5)").unwrap(), 1.0);
+    assert_eq!(evaluator.constants().len(), 2);
+
+    // With override allowed, the constant hides the function
+    evaluator.allow_constant_override(true);
+    evaluator.register_constant("sin", 3.0).unwrap();
+    assert_eq!(evaluator.evaluate("sin * 2").unwrap(), 6.0);
+    assert!(evaluator.register_constant("not", 3.0).is_err());
+}
+
+#[test]
+fn test_builtin_constant_override() {
+    let mut evaluator = Evaluator::new();
+    let err = evaluator.register_constant("pi", 3.0).unwrap_err();
+    assert_eq!(err, BtMathError::BuiltinConstant { name: "pi".to_owned(), span: Span::new(0, 2) });
+    assert_eq!(evaluator.evaluate("PI").unwrap(), std::f64::consts::PI);
+
+    evaluator.allow_constant_override(true);
+    evaluator.register_constant("pi", 3.0).unwrap();
+    assert_eq!(evaluator.evaluate("2 * PI").unwrap(), 6.0);
+}
+
+#[test]
+fn test_list_constants() {
+    let mut evaluator = Evaluator::new();
+    assert_eq!(
+        evaluator.constants(),
+        vec![("E".to_owned(), std::f64::consts::E), ("PI".to_owned(), std::f64::consts::PI)]
+    );
+    evaluator.register_constant("G", 9.81).unwrap();
+    evaluator.allow_constant_override(true);
+    evaluator.register_constant("E", 2.0).unwrap();
+    assert_eq!(
+        evaluator.constants(),
+        vec![("E".to_owned(), 2.0), ("G".to_owned(), 9.81), ("PI".to_owned(), std::f64::consts::PI)]
+    );
+}
+
+#[test]
+fn test_implicit_multiplication() {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_implicit_multiplication(true);
+    let mut ctx = Context::new();
+    ctx.set("x", 0.5);
+    assert_eq!(evaluator.evaluate("2PI").unwrap(), 2.0 * std::f64::consts::PI);
+    assert_eq!(evaluator.evaluate("3(4+5)").unwrap(), 27.0);
+    assert_eq!(evaluator.evaluate("(1+2)(3+4)").unwrap(), 21.0);
+    assert_eq!(evaluator.evaluate_with("2sin(x)", &ctx).unwrap(), 2.0 * 0.5f64.sin());
+    assert_eq!(evaluator.evaluate_with("-2x^2", &ctx).unwrap(), -0.5);
+    // Same precedence as `*`: 1/2PI is (1/2)*PI
+    assert_eq!(evaluator.evaluate("1/2PI").unwrap(), std::f64::consts::PI / 2.0);
+    assert_eq!(evaluator.parse("1/2PI").unwrap().to_string(), "((1 / 2) * PI)");
+}
+
+#[test]
+fn test_implicit_multiplication_disabled() {
+    assert!(matches!(evaluate_expression("2PI").unwrap_err(), BtMathError::MissingOperator { .. }));
+    assert!(matches!(evaluate_expression("(1+2)(3+4)").unwrap_err(), BtMathError::MissingOperator { .. }));
+}
+
+#[test]
+fn test_float_literals() {
+    assert_eq!(evaluate_expression("1e-3").unwrap(), 0.001);
+    assert_eq!(evaluate_expression("6.02E23 / 2").unwrap(), 3.01e23);
+    assert_eq!(evaluate_expression(".5 + 1.").unwrap(), 1.5);
+    assert_eq!(evaluate_expression("-1e5 + 2.5e+2").unwrap(), -99750.0);
+    assert_eq!(parse("1e5").unwrap().to_string(), "100000");
+    // E without digits after it is still the Euler constant
+    assert_eq!(evaluate_expression("2 * E").unwrap(), 2.0 * std::f64::consts::E);
+    let mut evaluator = Evaluator::new();
+    evaluator.set_implicit_multiplication(true);
+    assert_eq!(evaluator.evaluate("2E").unwrap(), 2.0 * std::f64::consts::E);
+}
+
+#[test]
+fn test_special_float_literals() {
+    let mut ctx = Context::new();
+    ctx.set("nan", 1.0);
+    assert_eq!(evaluate_with("nan + 1", &ctx).unwrap(), 2.0);
+    assert!(evaluate_expression("inf").is_err());
+
+    let mut evaluator = Evaluator::new();
+    evaluator.set_special_floats(true);
+    assert_eq!(evaluator.evaluate("-INF").unwrap(), f64::NEG_INFINITY);
+    assert_eq!(evaluator.evaluate("1 / infinity").unwrap(), 0.0);
+    assert!(evaluator.evaluate("nan * 0").unwrap().is_nan());
+}
+
+#[test]
+fn test_special_float_display() {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_special_floats(true);
+    let expr = evaluator.parse("inf - NaN").unwrap();
+    assert_eq!(expr.to_string(), "(1e309 - (0 / 0))");
+    // Parsed back without special floats
+    let parsed = parse(&expr.to_string()).unwrap();
+    assert_eq!(parsed.to_string(), expr.to_string());
+    assert!(parsed.eval().unwrap().is_nan());
+    let expr = Expr::new(ExprKind::Number(f64::NEG_INFINITY), Span::default());
+    assert_eq!(parse(&expr.to_string()).unwrap().eval().unwrap(), f64::NEG_INFINITY);
+    assert_eq!(parse("inf_x + 1e309").unwrap().to_string(), "(inf_x + 1e309)");
+}
+
+#[test]
+fn test_radix_literals() {
+    assert_eq!(evaluate_expression("0xFF + 0b101 + 0o17").unwrap(), 255.0 + 5.0 + 15.0);
+    assert_eq!(evaluate_expression("-0X1a * 0B1").unwrap(), -26.0);
+    let err = evaluate_expression("1 + 0b102").unwrap_err();
+    assert_eq!(err, BtMathError::InvalidNumber { literal: "0b102".to_owned(), span: Span::new(4, 9) });
+    assert_eq!(err.to_string(), "invalid number `0b102` at position 4");
+    assert!(matches!(evaluate_expression("0x").unwrap_err(), BtMathError::InvalidNumber { .. }));
+    assert!(matches!(evaluate_expression("0o8").unwrap_err(), BtMathError::InvalidNumber { .. }));
+    // 2^53 is the largest integer an f64 holds exactly: 54-bit literals would lose their low bits
+    assert_eq!(evaluate_expression("0x20000000000000").unwrap(), 9007199254740992.0);
+    let err = evaluate_expression("0x20000000000001 & 1").unwrap_err();
+    assert_eq!(err, BtMathError::InvalidNumber { literal: "0x20000000000001".to_owned(), span: Span::new(0, 16) });
+}
+
+#[test]
+fn test_bitwise_operators() {
+    assert_eq!(evaluate_expression("0xFF & (1 << 4)").unwrap(), 16.0);
+    assert_eq!(evaluate_expression("0b1100 | 0b0011").unwrap(), 15.0);
+    assert_eq!(evaluate_expression("6 xor 3").unwrap(), 5.0);
+    assert_eq!(evaluate_expression("~0").unwrap(), -1.0);
+    assert_eq!(evaluate_expression("~-1 + 256 >> 4").unwrap(), 16.0);
+    let mut ctx = Context::new();
+    ctx.set("flags", 12.0);
+    ctx.set("mask", 10.0);
+    assert_eq!(evaluate_with("flags XOR mask", &ctx).unwrap(), 6.0);
+    assert_eq!(compile("flags & ~mask").unwrap().eval(&mut ctx).unwrap(), 4.0);
+}
+
+#[test]
+fn test_bitwise_precedence() {
+    // | < xor < & < shifts < + -
+    assert_eq!(evaluate_expression("1 | 2 xor 3 & 6").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("1 << 2 + 1").unwrap(), 8.0);
+    assert_eq!(evaluate_expression("~1 * 2").unwrap(), -4.0);
+    assert_eq!(parse("1 | 2 & 3 << 1").unwrap().to_string(), "(1 | (2 & (3 << 1)))");
+}
+
+#[test]
+fn test_bitwise_non_integral() {
+    let err = evaluate_expression("1.5 & 1").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::DomainError { operation: "&".to_owned(), value: 1.5, span: Span::new(0, 7) }
+    );
+    assert_eq!(err.to_string(), "`&` is not defined for 1.5 at position 0");
+    assert!(matches!(evaluate_expression("~PI").unwrap_err(), BtMathError::DomainError { .. }));
+    assert!(matches!(evaluate_expression("1 << 64").unwrap_err(), BtMathError::DomainError { value, .. } if value == 64.0));
+    assert!(compile("2 xor 0.5").unwrap().eval(&mut Context::new()).is_err());
+    // Above 2^53 an f64 cannot hold every integer: the low bits of the operand are already lost
+    assert!(matches!(evaluate_expression("2^53 * 3 & 1").unwrap_err(), BtMathError::DomainError { value, .. } if value == 2f64.powi(53) * 3.0));
+    assert_eq!(evaluate_expression("9007199254740992 & 1").unwrap(), 0.0);
+    assert_eq!(evaluate_expression("9007199254740993.0 + 1e20").unwrap(), 9007199254740992.0 + 1e20);
+    // Decimal integers above 2^53 are valid literals outside of bitwise operators
+    assert_eq!(evaluate_expression("10000000000000000 * 2").unwrap(), 2e16);
+    assert_eq!(evaluate_expression("-9007199254740992 & 1").unwrap(), 0.0);
+    assert!(evaluate_expression("~(2^60)").is_err());
+    // A result above 2^53 fails instead of wrapping around or losing bits
+    assert!(matches!(evaluate_expression("1 << 63").unwrap_err(), BtMathError::DomainError { value, .. } if value == 1.0));
+    assert!(matches!(evaluate_expression("3 << 62").unwrap_err(), BtMathError::DomainError { .. }));
+    assert!(matches!(evaluate_expression("1 << 53 << 1").unwrap_err(), BtMathError::DomainError { value, .. } if value == 2f64.powi(53)));
+    assert!(evaluate_expression("(1 << 53) | 1").is_err());
+    assert_eq!(evaluate_expression("1 << 53").unwrap(), 2f64.powi(53));
+    assert_eq!(evaluate_expression("-1 << 53").unwrap(), -(2f64.powi(53)));
+}
+
+#[test]
+fn test_function_domain_errors() {
+    let err = evaluate_expression("1 + sqrt(-1)").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::DomainError { operation: "sqrt".to_owned(), value: -1.0, span: Span::new(4, 12) }
+    );
+    assert_eq!(err.to_string(), "`sqrt` is not defined for -1 at position 4");
+    for (expression, operation, value) in [
+        ("asin(2)", "asin", 2.0),
+        ("acosh(0.5)", "acosh", 0.5),
+        ("ln(0)", "ln", 0.0),
+        ("log(0, 8)", "log", 0.0),
+        ("log(2, -8)", "log", -8.0),
+        ("mod(1, 0)", "mod", 0.0),
+        ("1 % 0", "%", 0.0),
+        ("7 // 0", "//", 0.0),
+        ("0 // 0", "//", 0.0),
+    ] {
+        match evaluate_expression(expression).unwrap_err() {
+            BtMathError::DomainError { operation: op, value: v, .. } => {
+                assert_eq!((op.as_str(), v), (operation, value), "{}", expression)
+            }
+            err => panic!("{}: unexpected error {:?}", expression, err),
+        }
+    }
+    let mut ctx = Context::new();
+    ctx.set("x", -4.0);
+    let err = compile("2 * log10(x)").unwrap().eval(&mut ctx).unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::DomainError { operation: "log10".to_owned(), value: -4.0, span: Span::new(4, 12) }
+    );
+    // NaN is not a domain error, it propagates
+    let mut evaluator = Evaluator::new();
+    evaluator.set_special_floats(true);
+    assert!(evaluator.evaluate("sqrt(nan)").unwrap().is_nan());
+    assert_eq!(evaluate_expression("sqrt(0) + ln(1) + asin(1) * 0").unwrap(), 0.0);
+}
+
+#[test]
+fn test_modulo_and_floor_division() {
+    assert_eq!(evaluate_expression("7 % 3 + -7 % 3").unwrap(), 0.0);
+    assert_eq!(evaluate_expression("7.5 % 2").unwrap(), 1.5);
+    assert_eq!(evaluate_expression("mod(-7, 3) + mod(7, -3)").unwrap(), 3.0);
+    assert_eq!(evaluate_expression("7 // 2 + -7 // 2").unwrap(), -1.0);
+    // Same precedence as * and /, applied from left to right
+    assert_eq!(evaluate_expression("1 + 10 % 4 * 3").unwrap(), 7.0);
+    assert_eq!(evaluate_expression("20 // 3 // 2").unwrap(), 3.0);
+    assert_eq!(parse("2 * 9 // 4 % 3").unwrap().to_string(), "(((2 * 9) // 4) % 3)");
+}
+
+#[test]
+fn test_factorial() {
+    assert_eq!(evaluate_expression("5!").unwrap(), 120.0);
+    assert_eq!(evaluate_expression("0! + 1!").unwrap(), 2.0);
+    // n choose k
+    assert_eq!(evaluate_expression("10! / (3! * (10 - 3)!)").unwrap(), 120.0);
+    // Binds tighter than ^
+    assert_eq!(evaluate_expression("2^3!").unwrap(), 64.0);
+    assert_eq!(evaluate_expression("3!^2").unwrap(), 36.0);
+    assert_eq!(parse("2 * 3! - 1").unwrap().to_string(), "((2 * (3)!) - 1)");
+    assert!((evaluate_expression("0.5!").unwrap() - std::f64::consts::PI.sqrt() / 2.0).abs() < 1e-12);
+    assert_eq!(evaluate_expression("171!").unwrap(), f64::INFINITY);
+}
+
+#[test]
+fn test_factorial_negative_integer() {
+    let err = evaluate_expression("1 + (-3)!").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::DomainError { operation: "!".to_owned(), value: -3.0, span: Span::new(5, 9) }
+    );
+    assert!(matches!(evaluate_expression("3 + !").unwrap_err(), BtMathError::MissingOperand { .. }));
+}
+
+#[test]
+fn test_gamma() {
+    assert_eq!(evaluate_expression("gamma(5)").unwrap(), 24.0);
+    assert!((evaluate_expression("gamma(0.5)").unwrap() - std::f64::consts::PI.sqrt()).abs() < 1e-12);
+    assert!((evaluate_expression("gamma(-0.5)").unwrap() + 2.0 * std::f64::consts::PI.sqrt()).abs() < 1e-12);
+    assert!(matches!(evaluate_expression("gamma(-2)").unwrap_err(), BtMathError::DomainError { value, .. } if value == -2.0));
+}
+
+#[test]
+fn test_comparison_operators() {
+    let mut ctx = Context::new();
+    ctx.set("price", 120.0);
+    ctx.set("qty", 10.0);
+    assert_eq!(evaluate_with("price * qty > 1000", &ctx).unwrap(), 1.0);
+    assert_eq!(evaluate_with("price * qty <= 1000", &ctx).unwrap(), 0.0);
+    assert_eq!(evaluate_expression("1 + 1 == 2").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("2 != 2").unwrap(), 0.0);
+    assert_eq!(evaluate_expression("3 >= 3 & 1").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("(1 < 2) + (2 < 1)").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("3! != 6").unwrap(), 0.0);
+    assert_eq!(parse("1 << 2 < 5").unwrap().to_string(), "((1 << 2) < 5)");
+    assert_eq!(compile("qty < price").unwrap().eval(&mut ctx).unwrap(), 1.0);
+}
+
+#[test]
+fn test_logical_operators() {
+    assert_eq!(evaluate_expression("1 and 2").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("0 or -3").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("not 0 + not 5").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("1 < 2 && 3 < 2 || !(1 == 2)").unwrap(), 1.0);
+    // not binds less than comparisons, and less than not, or less than and
+    assert_eq!(evaluate_expression("not 2 > 3").unwrap(), 1.0);
+    assert_eq!(evaluate_expression("1 or 1 and 0").unwrap(), 1.0);
+    assert_eq!(parse("!a == b OR c && d").unwrap().to_string(), "(not((a == b)) or (c and d))");
+    // ! after an operand is still the factorial
+    assert_eq!(evaluate_expression("3! == 6 and !0").unwrap(), 1.0);
+}
+
+#[test]
+fn test_logical_short_circuit() {
+    let mut ctx = Context::new();
+    ctx.set("x", 0.0);
+    // y is not defined: it is an error only if it is evaluated
+    assert_eq!(evaluate_with("x != 0 and 10 / x > 2 and y", &ctx).unwrap(), 0.0);
+    assert_eq!(evaluate_with("x == 0 or y", &ctx).unwrap(), 1.0);
+    assert!(evaluate_with("x == 0 and y", &ctx).is_err());
+
+    let compiled = compile("x != 0 and 1.5 & x or x == 0 || y").unwrap();
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 1.0);
+    ctx.set("x", 4.0);
+    assert!(matches!(compiled.eval(&mut ctx).unwrap_err(), BtMathError::DomainError { .. }));
+    let compiled = compile("(x > 2 and x < 5) + (x > 5 or x == 4)").unwrap();
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 2.0);
+}
+
+#[test]
+fn test_conditional() {
+    let mut ctx = Context::new();
+    ctx.set("kwh", 150.0);
+    let tariff = "if(kwh <= 100, kwh * 0.1, 10 + (kwh - 100) * 0.2)";
+    assert_eq!(evaluate_with(tariff, &ctx).unwrap(), 20.0);
+    assert_eq!(evaluate_with("kwh > 100 ? 1 : 2", &ctx).unwrap(), 1.0);
+    assert_eq!(evaluate_expression("0 ? 1 : 0 ? 2 : 3").unwrap(), 3.0);
+    assert_eq!(evaluate_expression("1 ? 0 ? 1 : 2 : 3").unwrap(), 2.0);
+    assert_eq!(evaluate_expression("2 * (1 < 2 ? 3 + 1 : 5) - 1").unwrap(), 7.0);
+    assert_eq!(evaluate_expression("max(0 ? 1 : 2, 1)").unwrap(), 2.0);
+    let expr = parse("x > 0 ? x : -x").unwrap();
+    assert_eq!(expr.to_string(), "if((x > 0), x, -(x))");
+    assert_eq!(parse(&expr.to_string()).unwrap().to_string(), expr.to_string());
+}
+
+#[test]
+fn test_conditional_lazy_branches() {
+    let mut ctx = Context::new();
+    ctx.set("x", 0.0);
+    // y is not defined: it is an error only if its branch is chosen
+    assert_eq!(evaluate_with("if(x == 0, 1, y)", &ctx).unwrap(), 1.0);
+    assert_eq!(evaluate_with("x ? y : 1.5 & 1 == 1 ? 5 : 6", &ctx).unwrap_err().to_string(), "`&` is not defined for 1.5 at position 8");
+
+    let compiled = compile("x == 0 ? 1 : (x > 0 ? y : -y) * 2").unwrap();
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 1.0);
+    ctx.set("x", 1.0);
+    assert!(compiled.eval(&mut ctx).is_err());
+    ctx.set("y", 4.0);
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 8.0);
+    ctx.set("x", -1.0);
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), -8.0);
+}
+
+#[test]
+fn test_invalid_conditional() {
+    assert!(matches!(evaluate_expression("if(1, 2)").unwrap_err(), BtMathError::ArityMismatch { expected: 3, found: 2, .. }));
+    assert_eq!(
+        evaluate_expression("1 ? 2").unwrap_err(),
+        BtMathError::UnexpectedCharacter { character: '?', span: Span::new(2, 3) }
+    );
+    assert_eq!(
+        evaluate_expression("1 + 2 : 3").unwrap_err(),
+        BtMathError::UnexpectedCharacter { character: ':', span: Span::new(6, 7) }
+    );
+    assert!(evaluate_expression("(1 ? 2) : 3").is_err());
+}
+
+#[test]
+fn test_unary_minus_precedence() {
+    assert_eq!(evaluate_expression("-2^2").unwrap(), -4.0);
+    assert_eq!(evaluate_expression("(-2)^2").unwrap(), 4.0);
+    assert_eq!(evaluate_expression("2^-1").unwrap(), 0.5);
+    assert_eq!(evaluate_expression("-3!").unwrap(), -6.0);
+    assert_eq!(evaluate_expression("--2 - -2").unwrap(), 4.0);
+    assert_eq!(evaluate_expression("+2 * +(1 + 2)").unwrap(), 6.0);
+    assert_eq!(parse("-x^2 * 3").unwrap().to_string(), "(-((x ^ 2)) * 3)");
+    assert_eq!(parse("-2").unwrap().span, Span::new(0, 2));
+}
+
+#[test]
+fn test_right_associative_pow() {
+    assert_eq!(evaluate_expression("2^3^2").unwrap(), 512.0);
+    assert_eq!(parse("2^3^2").unwrap().to_string(), "(2 ^ (3 ^ 2))");
+    // The other operators still group from the left
+    assert_eq!(evaluate_expression("8 / 4 / 2 - 1 - 1").unwrap(), -1.0);
+}
+
+#[test]
+fn test_whitespace_separates_tokens() {
+    assert_eq!(
+        evaluate_expression("2 3").unwrap_err(),
+        BtMathError::MissingOperator { span: Span::new(2, 3) }
+    );
+    assert!(evaluate_expression("si n(1)").is_err());
+    assert!(evaluate_expression("1 < = 2").is_err());
+    assert_eq!(evaluate_expression("\t1 +\t2\r\n").unwrap(), 3.0);
+    let mut evaluator = Evaluator::new();
+    evaluator.set_implicit_multiplication(true);
+    assert!(evaluator.evaluate("2 3").is_err());
+    assert_eq!(evaluator.evaluate("2 PI").unwrap(), 2.0 * std::f64::consts::PI);
+}
+
+#[test]
+fn test_multi_line_expression() {
+    let mut ctx = Context::new();
+    ctx.set("kwh", 250.0);
+    let expression = "if(kwh <= 100,\n   kwh * 0.1,\n   10 + (kwh - 100) * 0.2)";
+    assert_eq!(evaluate_with(expression, &ctx).unwrap(), 40.0);
+    let err = evaluate_expression("1 +\n  2 $").unwrap_err();
+    assert_eq!(err, BtMathError::UnexpectedCharacter { character: '$', span: Span::new(8, 9) });
+}
+
+#[test]
+fn test_angle_modes() {
+    let mut evaluator = Evaluator::new();
+    assert!(close(evaluator.evaluate("sin(PI / 6)").unwrap(), 0.5));
+
+    evaluator.set_angle_mode(AngleMode::Degrees);
+    assert!(close(evaluator.evaluate("sin(30)").unwrap(), 0.5));
+    assert!(close(evaluator.evaluate("tan(45) + cos(60)").unwrap(), 1.5));
+    assert!(close(evaluator.evaluate("asin(1)").unwrap(), 90.0));
+    assert!(close(evaluator.evaluate("atan2(1, 1)").unwrap(), 45.0));
+    let compiled = evaluator.compile("acos(x)").unwrap();
+    let mut ctx = Context::new();
+    ctx.set("x", -1.0);
+    assert!(close(compiled.eval(&mut ctx).unwrap(), 180.0));
+
+    evaluator.set_angle_mode(AngleMode::Gradians);
+    assert!(close(evaluator.evaluate("sin(100)").unwrap(), 1.0));
+    assert!(close(evaluator.evaluate("atan(1)").unwrap(), 50.0));
+}
+
+#[test]
+fn test_angle_conversions() {
+    assert!(close(evaluate_expression("deg(PI)").unwrap(), 180.0));
+    assert!(close(evaluate_expression("rad(180)").unwrap(), std::f64::consts::PI));
+    // ° marks an angle in degrees whatever the angle mode
+    assert!(close(evaluate_expression("sin(30°)").unwrap(), 0.5));
+    assert!(close(evaluate_expression("-90°").unwrap(), -std::f64::consts::FRAC_PI_2));
+    let mut evaluator = Evaluator::new();
+    evaluator.set_angle_mode(AngleMode::Degrees);
+    assert!(close(evaluator.evaluate("cos(60°) + 45°").unwrap(), 45.5));
+    evaluator.set_angle_mode(AngleMode::Gradians);
+    assert!(close(evaluator.evaluate("90°").unwrap(), 100.0));
+    assert!(evaluate_expression("30 °").is_err());
+}
+
+#[test]
+fn test_degree_literal_mode_independent() {
+    let mut degrees = Evaluator::new();
+    degrees.set_angle_mode(AngleMode::Degrees);
+    // The ° is kept in the tree and converted with the mode of the evaluator that evaluates it
+    let expr = degrees.parse("sin(90°) + 90°").unwrap();
+    assert_eq!(expr.to_string(), "(sin(90°) + 90°)");
+    assert_eq!(parse(&expr.to_string()).unwrap().to_string(), expr.to_string());
+    assert!(close(expr.eval().unwrap(), 1.0 + std::f64::consts::FRAC_PI_2));
+    assert!(close(expr.compile().unwrap().eval(&mut Context::new()).unwrap(), 1.0 + std::f64::consts::FRAC_PI_2));
+    assert!(close(degrees.eval(&expr, &Context::new()).unwrap(), 91.0));
+    assert!(close(degrees.compile_expr(&parse("sin(90°)").unwrap()).unwrap().eval(&mut Context::new()).unwrap(), 1.0));
+    assert_eq!(parse("45°").unwrap().span, Span::new(0, 4));
+}
+
+#[test]
+fn test_hyperbolic_functions() {
+    assert!(close(evaluate_expression("cosh(1)^2 - sinh(1)^2").unwrap(), 1.0));
+    assert!(close(evaluate_expression("tanh(0.5)").unwrap(), 0.5f64.sinh() / 0.5f64.cosh()));
+    assert!(close(evaluate_expression("asinh(sinh(2)) + acosh(cosh(2)) + atanh(tanh(0.3))").unwrap(), 4.3));
+    assert!(matches!(evaluate_expression("acosh(0.5)").unwrap_err(), BtMathError::DomainError { value, .. } if value == 0.5));
+}
+
+#[test]
+fn test_reciprocal_trig_functions() {
+    assert!(close(evaluate_expression("sec(PI / 3)").unwrap(), 2.0));
+    assert!(close(evaluate_expression("csc(PI / 6)").unwrap(), 2.0));
+    assert!(close(evaluate_expression("cot(PI / 4)").unwrap(), 1.0));
+    assert!(close(evaluate_expression("asec(2)").unwrap(), std::f64::consts::FRAC_PI_3));
+    assert!(close(evaluate_expression("acsc(2)").unwrap(), std::f64::consts::FRAC_PI_6));
+    assert!(close(evaluate_expression("acot(1)").unwrap(), std::f64::consts::FRAC_PI_4));
+    let mut evaluator = Evaluator::new();
+    evaluator.set_angle_mode(AngleMode::Degrees);
+    assert!(close(evaluator.evaluate("sec(60) + acot(1)").unwrap(), 47.0));
+}
+
+#[test]
+fn test_number_part_functions() {
+    assert_eq!(evaluate_expression("floor(-1.5) + ceil(-1.5) + trunc(-1.5)").unwrap(), -4.0);
+    assert_eq!(evaluate_expression("sign(-3) + sign(0) + sign(0.5)").unwrap(), 0.0);
+    assert_eq!(evaluate_expression("frac(3.25) + frac(-1.25)").unwrap(), 0.0);
+    assert_eq!(evaluate_expression("round(2.5) + round(-2.5) + round(2.4)").unwrap(), 2.0);
+}
+
+#[test]
+fn test_round_digits() {
+    let mut ctx = Context::new();
+    ctx.set("price", 19.99);
+    assert_eq!(evaluate_with("round(price * 1.07, 2)", &ctx).unwrap(), 21.39);
+    assert_eq!(evaluate_expression("round(1234.5678, -2)").unwrap(), 1200.0);
+    assert_eq!(evaluate_expression("round(0.125, 2)").unwrap(), 0.13);
+    // Halves are rounded as written in decimal, even when their binary form is slightly below
+    assert_eq!(evaluate_expression("round(1.005, 2)").unwrap(), 1.01);
+    assert_eq!(evaluate_expression("round(0.145, 2)").unwrap(), 0.15);
+    assert_eq!(evaluate_expression("round(2.675, 2)").unwrap(), 2.68);
+    assert_eq!(evaluate_expression("round(-2.675, 2)").unwrap(), -2.68);
+    assert!(evaluate_expression("round(2.5, 0 / 0)").unwrap().is_nan());
+    // Digits beyond the range of an f64 leave the value unchanged, or round it to 0
+    assert_eq!(evaluate_expression("round(2.5, 400)").unwrap(), 2.5);
+    assert_eq!(evaluate_expression("round(1e300, 20)").unwrap(), 1e300);
+    assert_eq!(evaluate_expression("round(2.5, -400)").unwrap(), 0.0);
+    let err = evaluate_expression("round(1, 2, 3)").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::ArityMismatch { name: "round".to_owned(), expected: 2, found: 3, span: Span::new(0, 5) }
+    );
+    assert!(matches!(evaluate_expression("round()").unwrap_err(), BtMathError::ArityMismatch { expected: 1, found: 0, .. }));
+}
+
+#[test]
+fn test_rounding_mode() {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_rounding_mode(RoundingMode::HalfEven);
+    assert_eq!(evaluator.evaluate("round(2.5) + round(3.5) + round(-2.5)").unwrap(), 4.0);
+    assert_eq!(evaluator.evaluate("round(0.125, 2)").unwrap(), 0.12);
+    assert_eq!(evaluator.evaluate("round(0.145, 2) + round(2.675, 2)").unwrap(), 0.14 + 2.68);
+    let compiled = evaluator.compile("round(x)").unwrap();
+    let mut ctx = Context::new();
+    ctx.set("x", 0.5);
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 0.0);
+}
+
+#[test]
+fn test_aggregate_functions() {
+    assert_eq!(evaluate_expression("sum(1, 2, 3, 4)").unwrap(), 10.0);
+    assert_eq!(evaluate_expression("product(2, 3, 4) + product(5)").unwrap(), 29.0);
+    assert_eq!(evaluate_expression("avg(1, 2, 3, 6)").unwrap(), 3.0);
+    assert_eq!(evaluate_expression("min(3, -4, 1) + max(3, 7, -4, 2)").unwrap(), 3.0);
+    assert_eq!(evaluate_expression("median(5, 1, 3) + median(4, 1, 3, 2)").unwrap(), 5.5);
+    assert_eq!(evaluate_expression("variance(2, 4, 4, 4, 5, 5, 7, 9)").unwrap(), 4.0);
+    assert_eq!(evaluate_expression("stddev(2, 4, 4, 4, 5, 5, 7, 9)").unwrap(), 2.0);
+    let mut ctx = Context::new();
+    ctx.set("x", 2.0);
+    let compiled = compile("sum(x, x * 2, max(x, 10, pow(x, 3)), 1) / 4").unwrap();
+    assert_eq!(compiled.eval(&mut ctx).unwrap(), 4.25);
+}
+
+#[test]
+fn test_aggregate_functions_arity() {
+    let err = evaluate_expression("sum()").unwrap_err();
+    assert_eq!(
+        err,
+        BtMathError::ArityMismatch { name: "sum".to_owned(), expected: 1, found: 0, span: Span::new(0, 3) }
+    );
+    assert!(evaluate_expression(&format!("avg({})", vec!["1"; 100].join(", "))).unwrap() == 1.0);
+}